@@ -16,25 +16,33 @@ use std::thread;
 use std::time::Duration;
 use sysinfo::SystemExt;
 
+// One sample every 2 seconds for a minute
+const SAMPLES: usize = 30;
+
 fn main() {
     let mut builder = env_logger::Builder::new();
     builder.filter(Some("analytics_rs"), LevelFilter::Trace);
-    builder.filter(Some("mem_usage"), LevelFilter::Trace);
+    builder.filter(Some("system_info"), LevelFilter::Trace);
     builder.init();
 
-    let settings = match (env::var("KEEN_PROJECT_ID"), env::var("KEEN_API_KEY")) {
-        (Ok(project_id), Ok(write_key)) => ProjectSettings::new(None, &project_id, &write_key),
+    // KEEN_CUSTOM_DOMAIN_URL and KEEN_READ_KEY are optional
+    let custom_domain_url = env::var("KEEN_CUSTOM_DOMAIN_URL").ok();
+    let mut settings = match (env::var("KEEN_PROJECT_ID"), env::var("KEEN_API_KEY")) {
+        (Ok(project_id), Ok(write_key)) => ProjectSettings::new(custom_domain_url, &project_id, &write_key),
         _ => {
             panic!("KEEN_PROJECT_ID and KEEN_API_KEY have to be defined as environment variable");
         }
     };
+    if let Ok(read_key) = env::var("KEEN_READ_KEY") {
+        settings = settings.with_read_key(&read_key);
+    }
 
-    let mut client = KeenClient::new(settings, Some(Duration::from_secs(10)));
+    let client = KeenClient::new(settings, Some(Duration::from_secs(10)));
     client.start();
 
     let mut system = sysinfo::System::new();
 
-    loop {
+    for _ in 0..SAMPLES {
         system.refresh_all();
         let memory_used: f64 =
             system.get_used_memory() as f64 / system.get_total_memory() as f64 * 100.0;
@@ -43,14 +51,17 @@ fn main() {
             mem_used: memory_used,
         };
 
-        if let Err(e) =
-            client.add_event("system_info", &serde_json::to_value(&system_info).unwrap())
-        {
+        if let Err(e) = client.add_event_typed("system_info", &system_info) {
             error!("Event can't be added: {}", e);
         }
 
         thread::sleep(Duration::from_millis(2000));
     }
+
+    // Send the last events before exiting
+    if let Err(e) = client.flush(true) {
+        error!("Events can't be flushed: {}", e);
+    }
     client.stop();
 }
 
@@ -0,0 +1,18 @@
+use std::time::Instant;
+
+// Time source of the sending thread, used to know when the send intervals are elapsed. A mock
+// clock can be advanced by hand to check when the batches are sent: the thread still waits in
+// real time, KeenClient::tick wakes it up to read the clock again. The time has to be monotonic,
+// the timestamps of the events don't use it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+// Default clock, the monotonic clock of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
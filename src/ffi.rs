@@ -1,99 +1,339 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::os::raw::{c_int, c_char, c_ulonglong};
 use keenio::{Error, KeenClient, ProjectSettings};
 use std::time::Duration;
 use std::ptr;
 
-#[no_mangle]
-pub extern "C" fn Keen_New(c_custom_domain_url: *const c_char, c_project_key: *const c_char, c_api_key: *const c_char, send_interval: c_ulonglong) -> *mut KeenClient {
+// Result codes returned by the functions below
+pub const KEEN_SUCCESS: c_int = 1;
+pub const KEEN_ERROR_NOT_STARTED: c_int = -1;
+pub const KEEN_ERROR_INVALID_JSON: c_int = -2;
+pub const KEEN_ERROR_INVALID_UTF8: c_int = -3;
+pub const KEEN_ERROR_SEND: c_int = -4;
+pub const KEEN_ERROR_QUEUE_FULL: c_int = -5;
+pub const KEEN_ERROR_INVALID_HANDLE: c_int = -6;
+pub const KEEN_ERROR_NULL_ARGUMENT: c_int = -7;
+pub const KEEN_ERROR_INVALID_COLLECTION: c_int = -8;
+pub const KEEN_ERROR_TIMEOUT: c_int = -9;
 
-    let custom_domain_url_opt = if c_custom_domain_url != ptr::null() {
-        unsafe {CStr::from_ptr(c_custom_domain_url).to_str().ok()}
+fn error_code(error: &Error) -> c_int {
+    match *error {
+        Error::NotStarted => KEEN_ERROR_NOT_STARTED,
+        Error::QueueFull => KEEN_ERROR_QUEUE_FULL,
+        Error::Serialization(_)
+        | Error::InvalidEventShape
+        | Error::ReservedProperty(_)
+        | Error::TooDeep(_)
+        | Error::TooManyProperties(_) => KEEN_ERROR_INVALID_JSON,
+        Error::InvalidCollection(_) => KEEN_ERROR_INVALID_COLLECTION,
+        Error::Timeout => KEEN_ERROR_TIMEOUT,
+        _ => KEEN_ERROR_SEND,
     }
-    else {
-        None
-    };
+}
 
-    let project_key_opt = unsafe {
-        CStr::from_ptr(c_project_key).to_str().ok()
+// Return a static description of a result code
+#[no_mangle]
+pub extern "C" fn Keen_ErrorMessage(code: c_int) -> *const c_char {
+    let message: &'static [u8] = match code {
+        KEEN_SUCCESS => b"Success\0",
+        KEEN_ERROR_NOT_STARTED => b"The client is not started\0",
+        KEEN_ERROR_INVALID_JSON => b"The event is not a valid json object\0",
+        KEEN_ERROR_INVALID_UTF8 => b"A string is not valid UTF-8\0",
+        KEEN_ERROR_SEND => b"The event can't be sent\0",
+        KEEN_ERROR_QUEUE_FULL => b"The events queue is full\0",
+        KEEN_ERROR_INVALID_HANDLE => b"The keen handle is null\0",
+        KEEN_ERROR_NULL_ARGUMENT => b"A required string is null\0",
+        KEEN_ERROR_INVALID_COLLECTION => b"The collection name is not valid\0",
+        KEEN_ERROR_TIMEOUT => b"Keen didn't respond before the timeout\0",
+        _ => b"Unknown error\0",
     };
+    message.as_ptr() as *const c_char
+}
 
-    let api_key_opt = unsafe {
-        CStr::from_ptr(c_api_key).to_str().ok()
-    };
+// The exported functions take raw pointers from C and are not marked unsafe, the C caller has to
+// respect the Safety comment of each one. The pointers are checked for null, not for validity.
 
-    if let (Some(project_key), Some(api_key)) = (project_key_opt, api_key_opt) {
-        let setting = ProjectSettings::new(custom_domain_url_opt.map_or(None, |domain_name| Some(domain_name.to_string())), project_key, api_key);
-        Box::into_raw(Box::new(KeenClient::new(setting, Some(Duration::from_millis(send_interval))))) as *mut KeenClient
+// Ok(None) if the pointer is null, Err if the string is not valid UTF-8.
+// Safety: c_string is null or a nul terminated string valid for 'a.
+unsafe fn c_str<'a>(c_string: *const c_char) -> Result<Option<&'a str>, c_int> {
+    if c_string.is_null() {
+        return Ok(None);
     }
-    else {
-        0 as *mut KeenClient
+
+    CStr::from_ptr(c_string).to_str().map(Some).map_err(|_| KEEN_ERROR_INVALID_UTF8)
+}
+
+// The client can be used from several threads, only a shared reference is made.
+// Safety: keen_handle is null or a handle returned by Keen_New that is not freed during 'a.
+unsafe fn keen_from_handle<'a>(keen_handle: *mut KeenClient) -> Result<&'a KeenClient, c_int> {
+    keen_handle.as_ref().ok_or(KEEN_ERROR_INVALID_HANDLE)
+}
+
+// Only for the functions changing the client.
+// Safety: same as keen_from_handle, and no other thread uses the client during 'a.
+unsafe fn keen_mut_from_handle<'a>(keen_handle: *mut KeenClient) -> Result<&'a mut KeenClient, c_int> {
+    keen_handle.as_mut().ok_or(KEEN_ERROR_INVALID_HANDLE)
+}
+
+// Safety: the strings are null or nul terminated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_New(c_custom_domain_url: *const c_char, c_project_key: *const c_char, c_api_key: *const c_char, send_interval: c_ulonglong) -> *mut KeenClient {
+    // An invalid custom domain is ignored, the default one is used
+    let custom_domain_url_opt = unsafe { c_str(c_custom_domain_url) }.unwrap_or(None);
+
+    match (unsafe { c_str(c_project_key) }, unsafe { c_str(c_api_key) }) {
+        (Ok(Some(project_key)), Ok(Some(api_key))) => {
+            let setting = ProjectSettings::new(custom_domain_url_opt.map(|domain_name| domain_name.to_string()), project_key, api_key);
+            Box::into_raw(Box::new(KeenClient::new(setting, Some(Duration::from_millis(send_interval)))))
+        }
+        _ => ptr::null_mut(),
     }
 }
 
+// Safety: keen_handle is null or a handle of Keen_New, not used after the call.
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn Keen_Free(keen_handle: *mut KeenClient) {
+    if keen_handle.is_null() {
+        return;
+    }
+
     // Will be deleted when the keen will go out of scope
     let _keen = unsafe { Box::from_raw(keen_handle) };
 }
 
+// Called from the sending thread when a batch can't be sent. The message is only valid during the call.
+pub type KeenErrorCallback = extern "C" fn(code: c_int, message: *const c_char);
+
+// Has to be called before Keen_Start. A null callback removes the current one.
+// Safety: keen_handle is null or a handle of Keen_New, not used by another thread during the call.
 #[no_mangle]
-pub extern "C" fn Keen_Start(keen_handle: *mut KeenClient) {
-    let keen = unsafe { &mut *keen_handle };
-    keen.start();
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SetErrorCallback(keen_handle: *mut KeenClient, callback: Option<KeenErrorCallback>) -> c_int {
+    let keen = match unsafe { keen_mut_from_handle(keen_handle) } {
+        Ok(keen) => keen,
+        Err(code) => return code,
+    };
+
+    match callback {
+        Some(callback) => keen.set_error_callback(move |error, _dropped_events| {
+            let message = CString::new(error.to_string().replace('\0', "")).unwrap_or_default();
+            callback(error_code(error), message.as_ptr());
+        }),
+        None => keen.clear_error_callback(),
+    }
+    KEEN_SUCCESS
+}
+
+// Certificate validation is enabled by default, 0 disables it (ex: curl built with mbedtls
+// without the installed certificates). Used by the next requests.
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SetSslVerification(keen_handle: *mut KeenClient, ssl_verification: c_int) -> c_int {
+    let keen = match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => keen,
+        Err(code) => return code,
+    };
+
+    update_settings(keen, keen.settings().with_ssl_verification(ssl_verification != 0))
+}
+
+// Path of the CA bundle used to validate the server certificate instead of the default one
+// Safety: keen_handle is null or a handle of Keen_New, c_ca_bundle is null or nul terminated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SetCaBundle(keen_handle: *mut KeenClient, c_ca_bundle: *const c_char) -> c_int {
+    let keen = match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => keen,
+        Err(code) => return code,
+    };
+
+    match unsafe { c_str(c_ca_bundle) } {
+        Ok(Some(ca_bundle)) => update_settings(keen, keen.settings().with_ca_bundle(ca_bundle)),
+        Ok(None) => KEEN_ERROR_NULL_ARGUMENT,
+        Err(code) => code,
+    }
+}
+
+fn update_settings(keen: &KeenClient, settings: ProjectSettings) -> c_int {
+    match keen.update_settings(settings) {
+        Ok(_) => KEEN_SUCCESS,
+        Err(e) => {
+            error!("Settings can't be updated: {}", e);
+            error_code(&e)
+        }
+    }
 }
 
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
 #[no_mangle]
-pub extern "C" fn Keen_Stop(keen_handle: *mut KeenClient) {
-    let keen = unsafe { &mut *keen_handle };
-    keen.stop();
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_Start(keen_handle: *mut KeenClient) -> c_int {
+    match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => {
+            keen.start();
+            KEEN_SUCCESS
+        }
+        Err(code) => code,
+    }
 }
 
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_Stop(keen_handle: *mut KeenClient) -> c_int {
+    match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => {
+            keen.stop();
+            KEEN_SUCCESS
+        }
+        Err(code) => code,
+    }
+}
+
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn Keen_Flush(keen_handle: *mut KeenClient, wait: c_int) -> c_int {
-    let keen = unsafe { &mut *keen_handle };
+    let keen = match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => keen,
+        Err(code) => return code,
+    };
     let wait_flush = wait != 0;
-    if keen.flush(wait_flush).is_ok() {
-        1
+    match keen.flush(wait_flush) {
+        Ok(_) => KEEN_SUCCESS,
+        Err(e) => {
+            error!("Events can't be flushed: {}", e);
+            error_code(&e)
+        }
+    }
+}
+
+// The counters are read without locking. A null handle returns 0.
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_PendingEventCount(keen_handle: *mut KeenClient) -> c_ulonglong {
+    unsafe { keen_from_handle(keen_handle) }.map_or(0, |keen| keen.stats().pending_events as c_ulonglong)
+}
+
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SentEventCount(keen_handle: *mut KeenClient) -> c_ulonglong {
+    unsafe { keen_from_handle(keen_handle) }.map_or(0, |keen| keen.stats().events_sent as c_ulonglong)
+}
+
+// Safety: keen_handle is null or a handle of Keen_New that is not freed.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_DroppedEventCount(keen_handle: *mut KeenClient) -> c_ulonglong {
+    unsafe { keen_from_handle(keen_handle) }.map_or(0, |keen| keen.stats().events_dropped as c_ulonglong)
+}
+
+// Copy the message of the last error of the sending thread in buffer (truncated to buffer_size - 1
+// bytes, nul terminated). Return KEEN_SUCCESS if there is an error, 0 if the last batch was sent.
+// Safety: keen_handle is null or a handle of Keen_New, buffer has buffer_size writable bytes.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_LastError(keen_handle: *mut KeenClient, buffer: *mut c_char, buffer_size: usize) -> c_int {
+    let keen = match unsafe { keen_from_handle(keen_handle) } {
+        Ok(keen) => keen,
+        Err(code) => return code,
+    };
+    if buffer.is_null() || buffer_size == 0 {
+        return KEEN_ERROR_NULL_ARGUMENT;
     }
-    else {
-        -1
+
+    match keen.last_error() {
+        Some(error) => {
+            unsafe { copy_to_buffer(&error.to_string(), buffer, buffer_size) };
+            KEEN_SUCCESS
+        }
+        None => 0,
+    }
+}
+
+// Safety: buffer is not null and has buffer_size (not 0) writable bytes.
+unsafe fn copy_to_buffer(message: &str, buffer: *mut c_char, buffer_size: usize) {
+    // Don't cut a UTF-8 character
+    let mut message_len = message.len().min(buffer_size - 1);
+    while !message.is_char_boundary(message_len) {
+        message_len -= 1;
     }
+    ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, message_len);
+    *buffer.add(message_len) = 0;
 }
 
+// Safety: keen_handle is null or a handle of Keen_New, the strings are null or nul terminated.
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn Keen_AddEvent(keen_handle: *mut KeenClient, c_collection: *const c_char, c_event: *const c_char) -> c_int {
-    let keen = unsafe { &mut *keen_handle };
+    unsafe { add_event(keen_handle, c_collection, c_event, |keen, collection, json_event| keen.add_event(collection, json_event)) }
+}
+
+// Safety: keen_handle is null or a handle of Keen_New, the strings are null or nul terminated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_AddEventWithGeo(keen_handle: *mut KeenClient, c_collection: *const c_char, c_event: *const c_char) -> c_int {
+    unsafe { add_event(keen_handle, c_collection, c_event, |keen, collection, json_event| keen.add_event_with_geo_enrichment(collection, json_event)) }
+}
 
-    let collection_opt = unsafe {
-        CStr::from_ptr(c_collection).to_str().ok()
+// Send the event from the calling thread and return when Keen has accepted it, the client
+// doesn't need to be started. The call is bounded by the request timeout and the retries.
+// Safety: keen_handle is null or a handle of Keen_New, the strings are null or nul terminated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SendEventSync(keen_handle: *mut KeenClient, c_collection: *const c_char, c_event: *const c_char) -> c_int {
+    unsafe { add_event(keen_handle, c_collection, c_event, |keen, collection, json_event| {
+        keen.send_event_blocking(collection, json_event).map(|_| 0)
+    }) }
+}
+
+// Same as Keen_SendEventSync, returns KEEN_ERROR_TIMEOUT if Keen hasn't accepted the event after
+// timeout_ms milliseconds
+// Safety: keen_handle is null or a handle of Keen_New, the strings are null or nul terminated.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn Keen_SendEventSyncTimeout(keen_handle: *mut KeenClient, c_collection: *const c_char, c_event: *const c_char, timeout_ms: c_ulonglong) -> c_int {
+    unsafe { add_event(keen_handle, c_collection, c_event, |keen, collection, json_event| {
+        keen.send_event_blocking_timeout(collection, json_event, Duration::from_millis(timeout_ms)).map(|_| 0)
+    }) }
+}
+
+// Safety: same as keen_from_handle, the strings are null or nul terminated.
+unsafe fn add_event<F>(keen_handle: *mut KeenClient, c_collection: *const c_char, c_event: *const c_char, add: F) -> c_int
+    where F: Fn(&KeenClient, &str, &serde_json::Value) -> Result<usize, Error> {
+    let keen = match keen_from_handle(keen_handle) {
+        Ok(keen) => keen,
+        Err(code) => return code,
     };
 
-    let event_opt = unsafe {
-        CStr::from_ptr(c_event).to_str().ok()
+    let (collection, event) = match (c_str(c_collection), c_str(c_event)) {
+        (Ok(Some(collection)), Ok(Some(event))) => (collection, event),
+        (Err(code), _) | (_, Err(code)) => return code,
+        _ => return KEEN_ERROR_NULL_ARGUMENT,
     };
 
-    if let (Some(collection), Some(event)) = (collection_opt, event_opt) {
-        match serde_json::from_str(event) {
-            Ok(json_event) => {
-                match keen.add_event(collection, &json_event) {
-                    Ok(_) => return 1,
-                    Err(Error::NotStarted) => {
-                        trace!("Events can't be sent: {}", Error::NotStarted);
-                        return -1;
-                    },
-                    Err(e) => {
-                        error!("Event can't be added: {}", e);
-                        return -1;
-                    }
+    match serde_json::from_str(event) {
+        Ok(json_event) => {
+            match add(keen, collection, &json_event) {
+                Ok(_) => KEEN_SUCCESS,
+                Err(Error::NotStarted) => {
+                    trace!("Events can't be sent: {}", Error::NotStarted);
+                    KEEN_ERROR_NOT_STARTED
+                },
+                Err(e) => {
+                    error!("Event can't be added: {}", e);
+                    error_code(&e)
                 }
             }
-            Err(e) => {
-                error!("Can't build a json from string \"{}\". Error: {}", event, e);
-            }
+        }
+        Err(e) => {
+            error!("Can't build a json from string \"{}\". Error: {}", event, e);
+            KEEN_ERROR_INVALID_JSON
         }
     }
-
-    return -1;
 }
-
@@ -1,24 +1,61 @@
-use chrono::{SecondsFormat, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use curl;
-use curl::easy::{Easy, List};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand;
+use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use spool::EventSpool;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::error;
 use std::fmt;
+use std::future::Future;
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
+use clock::{Clock, SystemClock};
+use metrics_recorder;
+use log::LevelFilter;
+use transport::{CurlTransport, RateLimitedTransport, Response, Transport};
+use uuid::Uuid;
 
-const MAX_EVENTS_BY_REQUEST: u32 = 5000;
+const DEFAULT_MAX_EVENTS_BY_REQUEST: u32 = 5000;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 1024 * 1024;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+const DROPPED_EVENTS_WARNING_INTERVAL: usize = 1000;
+const MAX_COLLECTION_NAME_LENGTH: usize = 256;
+const DROP_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_USER_AGENT: &str = concat!("analytics-rs/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_METADATA_KEY: &str = "keen";
 
 #[derive(Debug)]
 pub enum Error {
     Io(String),
     Network(curl::Error),
+    Serialization(serde_json::Error),
     NotStarted,
+    QueueFull,
+    InvalidEventShape,
+    MissingReadKey,
+    InvalidCollection(String),
+    ReservedProperty(String),
+    SendFailed(String),
+    HttpStatus { code: u32, body: String },
+    Timeout,
+    TooDeep(usize),           // Nesting depth of the event
+    TooManyProperties(usize), // Number of properties of the event
 }
 
 impl From<curl::Error> for Error {
@@ -27,12 +64,45 @@ impl From<curl::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::Serialization(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error.to_string())
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Io(s) => write!(f, "{}", s),
             Error::Network(e) => write!(f, "{}", e),
+            Error::Serialization(e) => write!(f, "{}", e),
             Error::NotStarted => write!(f, "Thread is not running. Function \"start\" has to be called first"),
+            Error::QueueFull => write!(f, "Event queue is full"),
+            Error::InvalidEventShape => write!(f, "Event has to be a json object"),
+            Error::MissingReadKey => write!(f, "A read key is required to query Keen"),
+            Error::InvalidCollection(s) => write!(f, "Invalid collection name: {}", s),
+            Error::ReservedProperty(s) => write!(f, "Property \"{}\" is reserved by Keen", s),
+            Error::SendFailed(s) => write!(f, "Flushed events can't be sent: {}", s),
+            Error::HttpStatus { code, body } => write!(f, "Keen returned HTTP {}: {}", code, body),
+            Error::Timeout => write!(f, "Keen didn't respond before the timeout"),
+            Error::TooDeep(depth) => write!(f, "Event has {} levels of nested properties, more than the limit", depth),
+            Error::TooManyProperties(count) => write!(f, "Event has {} properties, more than the limit", count),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -42,308 +112,3798 @@ pub struct ProjectSettings {
     custom_domain_url: Option<String>,
     project_id: String,
     api_key: String,
+    request_timeout: Option<Duration>,
+    compression: bool,
+    authorization_header: bool,
+    ssl_verification: bool,
+    ca_bundle: Option<PathBuf>,
+    proxy: Option<ProxySettings>,
+    read_key: Option<String>,
+    headers: Vec<(String, String)>,
+    user_agent: String,
+    path_prefix: Option<String>,
+    dns_cache_timeout: Option<Duration>,
+    ip_version: IpVersion,
+    http2: bool,
+    failover_domain_urls: Vec<String>,
+    // Index in domain_urls() of the last domain that accepted a request, shared by the clones
+    active_domain: Arc<AtomicUsize>,
+}
+
+// Keys and credentials are not printed
+impl fmt::Debug for ProjectSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProjectSettings")
+            .field("custom_domain_url", &self.custom_domain_url)
+            .field("failover_domain_urls", &self.failover_domain_urls)
+            .field("project_id", &self.project_id)
+            .finish()
+    }
+}
+
+// Address family used to connect to Keen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+// Identify the project the events are sent to
+type ProjectKey = (Option<String>, String, String);
+
+#[derive(Clone)]
+struct ProxySettings {
+    url: String,
+    credentials: Option<(String, String)>,
 }
 
 impl ProjectSettings {
+    // custom_domain_url can be a http url, e.g. "http://localhost:8080" for a local mock server
     pub fn new(custom_domain_url: Option<String>, project_id: &str, api_key: &str) -> Self {
         ProjectSettings {
             custom_domain_url,
             project_id: project_id.to_owned(),
             api_key: api_key.to_owned(),
+            request_timeout: None,
+            compression: false,
+            authorization_header: false,
+            ssl_verification: true,
+            ca_bundle: None,
+            proxy: None,
+            read_key: None,
+            headers: Vec::new(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            path_prefix: None,
+            dns_cache_timeout: None,
+            ip_version: IpVersion::Any,
+            http2: false,
+            failover_domain_urls: Vec::new(),
+            active_domain: Arc::new(AtomicUsize::new(0)),
         }
     }
-}
-
-#[derive(Clone)]
-pub struct KeenClient {
-    settings: ProjectSettings,
-    send_interval: Option<Duration>,
-    // Keep the sender/receiver in a Mutex because the KeenClient struct has to be sync in DenRouter
-    sender: Arc<Mutex<Option<Sender<Event>>>>,          // Use to send events to the thread
-    receiver_sync: Arc<Mutex<Option<Receiver<()>>>>,    // Use to wait the end of a task in the thread
-    thread_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-}
 
-impl KeenClient {
-    pub fn new(settings: ProjectSettings, send_interval: Option<Duration>) -> Self {
-        KeenClient {
-            settings: settings,
-            sender: Arc::new(Mutex::new(None)),
-            receiver_sync: Arc::new(Mutex::new(None)),
-            thread_handle: Arc::new(Mutex::new(None)),
-            send_interval: send_interval,
-        }
+    fn key(&self) -> ProjectKey {
+        (self.custom_domain_url.clone(), self.project_id.clone(), self.api_key.clone())
     }
 
-    pub fn start(&mut self) {
-        let (sender_event, receiver_event) = channel();
-        let (sender_sync, receiver_sync) = channel();
+    // Key used by KeenClient::query, the api key is the write key
+    pub fn with_read_key(mut self, read_key: &str) -> Self {
+        self.read_key = Some(read_key.to_owned());
+        self
+    }
 
-        let mut sender_event_opt = self.sender.lock().unwrap();
-        let mut receiver_sync_opt = self.receiver_sync.lock().unwrap();
-        if sender_event_opt.is_none() && receiver_sync_opt.is_none() {
-            *sender_event_opt = Some(sender_event);
-            *receiver_sync_opt = Some(receiver_sync);
+    // Timeout applied to the connection and to the whole request. 30 seconds if not set.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
 
-            let settings = self.settings.clone();
-            let send_interval = self.send_interval.clone();
+    // Send the body gzip encoded
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
 
-            self.thread_handle = Arc::new(Mutex::new(Some(thread::spawn(move || {
-                send_events_thread(receiver_event, sender_sync, settings, send_interval);
-            }))));
-        }
+    // Send the api key in the Authorization header instead of the url query string,
+    // so it doesn't end up in proxy logs
+    pub fn with_authorization_header(mut self, authorization_header: bool) -> Self {
+        self.authorization_header = authorization_header;
+        self
     }
 
-    pub fn stop(&mut self) {
-        {
-            // We drop the sender. The receiver will fail and thread will close.
-            self.sender.lock().unwrap().take();
-        }
+    // Certificate validation is enabled by default. It can be disabled when curl uses mbedtls
+    // and the installed certificates are not provided to mbedtls (wayk windows has that problem).
+    pub fn with_ssl_verification(mut self, ssl_verification: bool) -> Self {
+        self.ssl_verification = ssl_verification;
+        self
+    }
 
-        // Wait the end of the thread
-        if let Some(handle) = self.thread_handle.lock().unwrap().take() {
-            let _ = handle.join();
-        }
+    // CA bundle used to validate the server certificate instead of the default one
+    pub fn with_ca_bundle<P: Into<PathBuf>>(mut self, ca_bundle: P) -> Self {
+        self.ca_bundle = Some(ca_bundle.into());
+        self
     }
 
-    pub fn flush(&mut self, wait: bool) -> Result<(), Error> {
-        // Send the event FLUSH
-        let sender = self.sender.lock().unwrap();
+    // HTTP or SOCKS proxy url (ex: "http://proxy:3128", "socks5://proxy:1080").
+    // HTTPS_PROXY/HTTP_PROXY environment variables are used if no proxy is set.
+    pub fn with_proxy(mut self, proxy_url: &str) -> Self {
+        let credentials = self.proxy.take().and_then(|proxy| proxy.credentials);
+        self.proxy = Some(ProxySettings {
+            url: proxy_url.to_owned(),
+            credentials,
+        });
+        self
+    }
 
-        if let Some(ref sender) = *sender {
-            sender.send(Event::Flush(wait)).map_err(|e| Error::Io(e.to_string()))?;
-            if wait {
-                let receiver_sync_opt = self.receiver_sync.lock().unwrap();
-                if let Some(ref receiver_sync) = *receiver_sync_opt {
-                    receiver_sync.recv().map_err(|e| Error::Io(e.to_string()))?;
-                }
-            }
-            Ok(())
+    // Path between the domain and /3.0/projects, for a Keen-compatible endpoint behind a
+    // proxy (ex: "/analytics")
+    pub fn with_path_prefix(mut self, path_prefix: &str) -> Self {
+        let path_prefix = path_prefix.trim_matches('/');
+        self.path_prefix = if path_prefix.is_empty() {
+            None
         } else {
-            Err(Error::NotStarted)
-        }
+            Some(format!("/{}", path_prefix))
+        };
+        self
     }
 
-    pub fn add_event(&self, collection: &str, json: &serde_json::Value) -> Result<(), Error> {
-        self.add_event_with_param(collection, json, false)
+    // How long the resolved addresses are kept by the connection, 60 seconds (libcurl default)
+    // if not set
+    pub fn with_dns_cache_timeout(mut self, dns_cache_timeout: Duration) -> Self {
+        self.dns_cache_timeout = Some(dns_cache_timeout);
+        self
     }
 
-    pub fn add_event_with_geo_enrichment(
-        &self,
-        collection: &str,
-        json: &serde_json::Value,
-    ) -> Result<(), Error> {
-        self.add_event_with_param(collection, json, true)
+    // Only connect with IPv4 (or IPv6), for the hosts with a broken IPv6 route. Any by default.
+    pub fn with_ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
     }
 
-    fn add_event_with_param(
-        &self,
-        collection: &str,
-        json: &serde_json::Value,
-        add_ip_geo: bool,
-    ) -> Result<(), Error> {
-        // Add a timestamp
-        let mut json_clone = json.clone();
-        if let Some(object) = json_clone.as_object_mut() {
-            let mut keen_info =
-                KeenInfo::new(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
-            if add_ip_geo {
-                keen_info.add_addon(KeenAddons::build_ip_geo_addons(
-                    "ip_address",
-                    "ip_geo_info",
-                    true,
-                ));
-                object.insert("ip_address".to_string(), json!("${keen.ip}"));
-            }
+    // Domain tried, in the order they are added, when the events can't be sent to the custom
+    // domain (or to the Keen domain). The last domain that worked is used for the next requests.
+    pub fn with_failover_domain_url(mut self, domain_url: &str) -> Self {
+        self.failover_domain_urls.push(domain_url.to_owned());
+        self
+    }
+
+    fn domain_urls(&self) -> Vec<&str> {
+        let domain_url = self.custom_domain_url.as_ref().map_or("https://api.keen.io", |url| url.as_str());
+        let mut domain_urls = vec![domain_url.trim_end_matches('/')];
+        domain_urls.extend(self.failover_domain_urls.iter().map(|url| url.trim_end_matches('/')));
+        domain_urls
+    }
+
+    // Replace the User-Agent header, "analytics-rs/<version>" by default
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    // Product token added after the User-Agent (ex: "wayk/2.1"), to identify the application
+    pub fn with_user_agent_product(mut self, product: &str) -> Self {
+        self.user_agent = format!("{} {}", self.user_agent, product);
+        self
+    }
+
+    // Use HTTP/2 for the https requests when the server supports it (curl has to be built with
+    // nghttp2), HTTP/1.1 otherwise. The connection is kept between the batches, they are still
+    // sent one at a time.
+    pub fn with_http2(mut self, http2: bool) -> Self {
+        self.http2 = http2;
+        self
+    }
+
+    // Additional header sent with every request (ex: "X-Tenant-Id" for a gateway)
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
 
-            object.insert(
-                "keen".to_string(),
-                serde_json::to_value(&keen_info).unwrap(),
-            );
+    // Credentials used to authenticate to the proxy set with with_proxy
+    pub fn with_proxy_credentials(mut self, username: &str, password: &str) -> Self {
+        if let Some(ref mut proxy) = self.proxy {
+            proxy.credentials = Some((username.to_owned(), password.to_owned()));
         }
+        self
+    }
 
-        let event = Event::KeenEvent(collection.to_owned(), json_clone);
+    // The connection options, for the transports
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
 
-        // Send the event
-        let sender = self.sender.lock().unwrap();
-        if let Some(ref sender) = *sender {
-            sender.send(event).map_err(|e| Error::Io(e.to_string()))
-        } else {
-            Err(Error::NotStarted)
+    pub fn ssl_verification(&self) -> bool {
+        self.ssl_verification
+    }
+
+    pub fn ca_bundle(&self) -> Option<&Path> {
+        self.ca_bundle.as_deref()
+    }
+
+    pub fn proxy_url(&self) -> Option<&str> {
+        self.proxy.as_ref().map(|proxy| proxy.url.as_str())
+    }
+
+    // Username and password
+    pub fn proxy_credentials(&self) -> Option<(&str, &str)> {
+        self.proxy
+            .as_ref()
+            .and_then(|proxy| proxy.credentials.as_ref())
+            .map(|(username, password)| (username.as_str(), password.as_str()))
+    }
+
+    pub fn dns_cache_timeout(&self) -> Option<Duration> {
+        self.dns_cache_timeout
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    pub fn http2(&self) -> bool {
+        self.http2
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
         }
     }
+
+    // No retry, the batch is dropped after the first failure
+    pub fn none() -> Self {
+        RetryPolicy::new(1, Duration::from_millis(0))
+    }
+
+    // Delay to wait after the failed attempt (1-based): base_delay * 2^(attempt - 1)
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base_delay
+            .checked_mul(factor)
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
 }
 
-fn send_events_thread(
-    receiver: Receiver<Event>,
-    sender_sync: Sender<()>,
-    settings: ProjectSettings,
-    send_interval: Option<Duration>,
-) {
-    let mut send_events = false;
-    let mut notify_caller = false;
-    let mut events_qty = 0u32;
-    let mut events = HashMap::new();
-    let mut stop_thread = false;
-    let mut now = SystemTime::now();
+// What add_event does when the maximum number of pending events is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    DropNewest, // The new event is rejected with Error::QueueFull
+    DropOldest, // The oldest event not received by the thread yet is dropped
+    Block,      // add_event waits until the thread sends some events
+}
 
-    loop {
-        match send_interval.as_ref() {
-            Some(interval) => {
-                // Calculate next timeout before sending events
-                let elapsed = now.elapsed().unwrap_or_else(|_| *interval);
-                let timeout = if *interval > elapsed {
-                    *interval - elapsed
-                } else {
-                    Duration::from_millis(0)
-                };
+#[derive(Clone, Copy, Debug)]
+struct QueueLimit {
+    max_queue: usize,
+    policy: BackpressurePolicy,
+}
 
-                match receiver.recv_timeout(timeout) {
-                    Ok(Event::KeenEvent(collection, json)) => {
-                        events_qty += 1;
-                        let collection = events.entry(collection).or_insert(Vec::new());
-                        collection.push(json);
-                    }
-                    Ok(Event::Flush(notify)) => {
-                        send_events = true;
-                        notify_caller = notify;
-                    }
-                    Err(RecvTimeoutError::Timeout) => {
-                        send_events = true;
-                    }
-                    Err(_) => {
-                        stop_thread = true;
-                    }
-                }
-            }
-            None => match receiver.recv() {
-                Ok(Event::KeenEvent(collection, json)) => {
-                    let collection = events.entry(collection).or_insert(Vec::new());
-                    collection.push(json);
-                    send_events = true;
-                }
-                Ok(Event::Flush(notify)) => {
-                    send_events = true;
-                    notify_caller = notify;
-                }
-                Err(_) => {
-                    stop_thread = true;
-                },
-            },
+// Result of a request sent by the thread, received from results_stream
+#[derive(Clone, Debug)]
+pub struct BatchResult {
+    pub collection_counts: HashMap<String, usize>, // Events of the request in each collection
+    pub outcome: BatchOutcome,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Sent { events_accepted: usize }, // Some events can be rejected by Keen
+    Failed(String),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeenStats {
+    pub events_accepted: usize, // Events added to the queue
+    pub events_sent: usize,     // Events accepted by Keen
+    pub events_dropped: usize,  // Events dropped because the queue was full
+    pub batches: usize,         // Requests sent to Keen
+    pub send_failures: usize,   // Requests that failed
+    pub pending_events: usize,  // Events waiting to be sent
+    pub events_sampled: usize,  // Events skipped by the sampling
+    pub events_expired: usize,  // Events dropped because they were older than the max event age
+    pub events_filtered: usize, // Events dropped by the transform hook
+    pub last_flush_reason: Option<FlushReason>,
+}
+
+// Why the thread sent the buffered events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushReason {
+    Stop,               // The client is stopped
+    Flush,              // flush or flush_async was called
+    MaxCount,           // max_events_per_request events are waiting
+    Count,              // flush_at_count events are waiting
+    Bytes,              // The waiting events are bigger than flush_at_bytes
+    Age,                // The oldest waiting event is older than flush_at_age
+    Interval,           // The send interval is elapsed, or there is no interval
+    CollectionFull,     // A collection has max_events_per_collection events, only the full collections are sent
+    CollectionInterval, // The interval of a collection is elapsed, only this collection is sent
+}
+
+// Stored in QueueState::last_flush_reason at their index + 1, 0 before the first flush
+const FLUSH_REASONS: [FlushReason; 9] = [
+    FlushReason::Stop,
+    FlushReason::Flush,
+    FlushReason::MaxCount,
+    FlushReason::Count,
+    FlushReason::Bytes,
+    FlushReason::Age,
+    FlushReason::Interval,
+    FlushReason::CollectionFull,
+    FlushReason::CollectionInterval,
+];
+
+// Counters shared between the client and the thread
+#[derive(Default)]
+struct QueueState {
+    pending_events: AtomicUsize, // Events added but not sent yet
+    dropped_events: AtomicUsize,
+    accepted_events: AtomicUsize,
+    sent_events: AtomicUsize,
+    batches: AtomicUsize,
+    send_failures: AtomicUsize,
+    sampled_events: AtomicUsize,
+    expired_events: AtomicUsize,
+    filtered_events: AtomicUsize,
+    last_flush_reason: AtomicUsize, // Encoded with FLUSH_REASONS
+    last_error: Mutex<Option<String>>, // Error of the last request, None after a success
+    disabled: AtomicBool,              // Set by set_enabled(false)
+    sequence_numbers: Mutex<HashMap<String, u64>>, // Last sequence number of each collection
+    results_senders: Mutex<Vec<Sender<BatchResult>>>, // One by results_stream receiver
+    recent_events: Mutex<VecDeque<(String, serde_json::Value)>>, // Kept by ring_buffer instead of sent
+    waiting_events: Mutex<VecDeque<Event>>, // DropOldest: events not received by the thread yet
+    space_lock: Mutex<()>,
+    space_available: Condvar,
+}
+
+impl QueueState {
+    fn pending(&self) -> usize {
+        self.pending_events.load(Ordering::SeqCst)
+    }
+
+    // The receivers that are dropped are forgotten
+    fn publish_result(&self, events: &HashMap<String, Vec<serde_json::Value>>, outcome: BatchOutcome) {
+        let mut results_senders = self.results_senders.lock().unwrap();
+        if results_senders.is_empty() {
+            return;
         }
 
-        if send_events || events_qty >= MAX_EVENTS_BY_REQUEST || stop_thread {
-            now = SystemTime::now();
-            if !events.is_empty() {
-                trace!("Sending events: {} events to send!", events_qty);
-                let body = serde_json::to_string(&events).unwrap();
-                match post_to_keen(&settings, &body) {
-                    Ok(_) => {
-                        trace!("Events sent: {}", body);
-                    },
-                    Err(Error::NotStarted) => {
-                        trace!("Events can't be sent: {}", Error::NotStarted);
-                    },
-                    Err(e) => {
-                        error!("Events can't be sent: {}", e);
-                    },
-                }
-                events.clear();
-            }
-            send_events = false;
-            events_qty = 0;
+        let result = BatchResult {
+            collection_counts: collection_counts(events),
+            outcome,
+        };
+        results_senders.retain(|sender| sender.send(result.clone()).is_ok());
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::SeqCst)
+    }
+
+    fn stats(&self) -> KeenStats {
+        KeenStats {
+            events_accepted: self.accepted_events.load(Ordering::SeqCst),
+            events_sent: self.sent_events.load(Ordering::SeqCst),
+            events_dropped: self.dropped_events.load(Ordering::SeqCst),
+            batches: self.batches.load(Ordering::SeqCst),
+            send_failures: self.send_failures.load(Ordering::SeqCst),
+            pending_events: self.pending(),
+            events_sampled: self.sampled_events.load(Ordering::SeqCst),
+            events_expired: self.expired_events.load(Ordering::SeqCst),
+            events_filtered: self.filtered_events.load(Ordering::SeqCst),
+            last_flush_reason: self.last_flush_reason(),
         }
+    }
 
-        // Notify the caller that the flush is done
-        if notify_caller {
-            let _ = sender_sync.send(());
+    fn last_flush_reason(&self) -> Option<FlushReason> {
+        match self.last_flush_reason.load(Ordering::SeqCst) {
+            0 => None,
+            index => FLUSH_REASONS.get(index - 1).cloned(),
         }
+    }
 
-        if stop_thread {
-            break;
+    fn set_last_flush_reason(&self, reason: FlushReason) {
+        let index = FLUSH_REASONS.iter().position(|&flush_reason| flush_reason == reason).unwrap_or(0);
+        self.last_flush_reason.store(index + 1, Ordering::SeqCst);
+    }
+
+    fn add(&self, qty: usize) -> usize {
+        let pending_events = self.pending_events.fetch_add(qty, Ordering::SeqCst) + qty;
+        metrics_recorder::pending_events(pending_events);
+        pending_events
+    }
+
+    fn remove(&self, qty: usize) {
+        let pending_events = self.pending_events.fetch_sub(qty, Ordering::SeqCst).wrapping_sub(qty);
+        metrics_recorder::pending_events(pending_events);
+        self.notify_space();
+    }
+
+    // Wake up the callers blocked by a full queue
+    fn notify_space(&self) {
+        let _guard = self.space_lock.lock().unwrap();
+        self.space_available.notify_all();
+    }
+
+    fn events_dropped(&self, qty: usize) {
+        let previous_dropped_events = self.dropped_events.fetch_add(qty, Ordering::SeqCst);
+        let dropped_events = previous_dropped_events + qty;
+        if previous_dropped_events == 0
+            || previous_dropped_events / DROPPED_EVENTS_WARNING_INTERVAL != dropped_events / DROPPED_EVENTS_WARNING_INTERVAL
+        {
+            warn!("Event queue is full: {} events dropped so far", dropped_events);
         }
     }
 }
 
-fn post_to_keen(settings: &ProjectSettings, body: &str) -> Result<(), Error> {
-    // Prepare curl request
-    let mut easy = Easy::new();
+// Sent by the thread to the caller of a flush: number of events sent, or the error
+type FlushResult = Result<usize, String>;
 
-    // Don't validate the certificate since curl request will fail if mbedtlsis used
-    // and installed certificates are not provided to mbedtls (wayk windows has that problem).
-    let _ = easy.ssl_verify_host(false);
-    let _ = easy.ssl_verify_peer(false);
+// Called by the thread with the error and the number of events dropped
+type ErrorCallback = Arc<dyn Fn(&Error, usize) + Send + Sync>;
 
-    let domain_url = settings.custom_domain_url.as_ref().map_or("https://api.keen.io".to_string(), |url| url.to_string());
-    let url = format!(
-        "{}/3.0/projects/{}/events?api_key={}", domain_url, settings.project_id, settings.api_key
-    );
-    easy.url(&url)?;
-    easy.post(true)?;
+// Called by the thread with the number of events accepted by Keen in each collection
+type SuccessCallback = Arc<dyn Fn(&HashMap<String, usize>) + Send + Sync>;
 
-    // Set content-type
-    let mut list = List::new();
-    list.append("Content-Type: application/json")?;
-    easy.http_headers(list)?;
+// Called by the thread with the collection and the event before it's queued, None drops the event
+type TransformCallback = Arc<dyn Fn(&str, serde_json::Value) -> Option<serde_json::Value> + Send + Sync>;
 
-    // Set body
-    easy.post_fields_copy(body.as_ref())?;
+// Create the transport of each thread (and of the blocking calls)
+type TransportFactory = Arc<dyn Fn() -> Box<dyn Transport> + Send + Sync>;
 
-    // Send request
-    easy.perform()?;
-    Ok(())
+// Options of the client, the thread gets a copy when it starts
+#[derive(Clone)]
+struct ClientConfig {
+    send_interval: Option<Duration>,
+    retry_policy: RetryPolicy,
+    spool_dir: Option<PathBuf>,
+    queue_limit: Option<QueueLimit>,
+    default_properties: Option<serde_json::Map<String, serde_json::Value>>,
+    on_error: Option<ErrorCallback>,
+    on_success: Option<SuccessCallback>,
+    max_events_per_request: u32,
+    flush_at_count: Option<u32>,
+    flush_at_bytes: Option<usize>,
+    flush_at_age: Option<Duration>,
+    max_request_bytes: usize,
+    collection_prefix: Option<String>,
+    event_id_property: Option<String>,
+    request_id_property: Option<String>,
+    sequence_property: Option<String>,
+    thread_name: Option<String>,
+    dry_run: bool,
+    prewarm_connection: bool,
+    ring_buffer: Option<usize>,
+    transport: Option<TransportFactory>,
+    sample_rate: Option<f64>,
+    collection_sample_rates: HashMap<String, f64>,
+    sanitize_properties: bool,
+    flatten_separator: Option<String>,
+    max_property_depth: Option<usize>,
+    max_properties: Option<usize>,
+    heartbeat: Option<(String, serde_json::Value)>,
+    coalesce_property: Option<String>,
+    send_interval_jitter: Option<f64>,
+    timestamp_format: SecondsFormat,
+    collection_send_intervals: HashMap<String, Duration>,
+    max_event_age: Option<Duration>,
+    transform: Option<TransformCallback>,
+    max_requests_per_second: Option<f64>,
+    max_events_per_collection: Option<usize>,
+    metadata_key: String,
+    clock: Arc<dyn Clock>,
+    success_log_level: LevelFilter,
+    failure_log_level: LevelFilter,
 }
 
-#[derive(Debug)]
-enum Event {
-    KeenEvent(String, serde_json::Value),
-    Flush(bool),
+impl ClientConfig {
+    // Name of the collection with the prefix, rejected if it doesn't follow the rules of Keen
+    fn collection_name(&self, collection: &str) -> Result<String, Error> {
+        let collection_name = match self.collection_prefix {
+            Some(ref collection_prefix) => format!("{}{}", collection_prefix, collection),
+            None => collection.to_owned(),
+        };
+        validate_collection_name(&collection_name)?;
+        Ok(collection_name)
+    }
+
+    fn new_transport(&self) -> Box<dyn Transport> {
+        let transport: Box<dyn Transport> = match self.transport {
+            Some(ref transport) => transport(),
+            None => Box::new(CurlTransport::new()),
+        };
+
+        match self.max_requests_per_second {
+            Some(max_requests_per_second) => Box::new(RateLimitedTransport::new(transport, max_requests_per_second)),
+            None => transport,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct KeenInfo {
-    timestamp: String,
-    addons: Vec<KeenAddons>,
+pub struct KeenClientBuilder {
+    settings: ProjectSettings,
+    config: ClientConfig,
 }
 
-impl KeenInfo {
-    fn new(timestamp: String) -> Self {
-        KeenInfo {
-            timestamp,
-            addons: Vec::new(),
+impl KeenClientBuilder {
+    pub fn new(settings: ProjectSettings) -> Self {
+        KeenClientBuilder {
+            settings,
+            config: ClientConfig {
+                send_interval: None,
+                retry_policy: RetryPolicy::none(),
+                spool_dir: None,
+                queue_limit: None,
+                default_properties: None,
+                on_error: None,
+                on_success: None,
+                max_events_per_request: DEFAULT_MAX_EVENTS_BY_REQUEST,
+                flush_at_count: None,
+                flush_at_bytes: None,
+                flush_at_age: None,
+                max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+                collection_prefix: None,
+                event_id_property: None,
+                request_id_property: None,
+                sequence_property: None,
+                thread_name: None,
+                dry_run: false,
+                prewarm_connection: false,
+                ring_buffer: None,
+                transport: None,
+                sample_rate: None,
+                collection_sample_rates: HashMap::new(),
+                sanitize_properties: false,
+                flatten_separator: None,
+                max_property_depth: None,
+                max_properties: None,
+                heartbeat: None,
+                coalesce_property: None,
+                send_interval_jitter: None,
+                timestamp_format: SecondsFormat::Millis,
+                collection_send_intervals: HashMap::new(),
+                max_event_age: None,
+                transform: None,
+                max_requests_per_second: None,
+                max_events_per_collection: None,
+                metadata_key: DEFAULT_METADATA_KEY.to_string(),
+                clock: Arc::new(SystemClock),
+                success_log_level: LevelFilter::Trace,
+                failure_log_level: LevelFilter::Error,
+            },
         }
     }
 
-    fn add_addon(&mut self, addon: KeenAddons) {
-        self.addons.push(addon);
+    // Events are sent every send_interval. Without interval, each event is sent as soon as it's added.
+    pub fn send_interval(mut self, send_interval: Duration) -> Self {
+        self.config.send_interval = Some(send_interval);
+        self
     }
-}
-#[derive(Serialize, Deserialize)]
-struct KeenAddons {
-    name: String,
-    input: KeenInput,
-    output: String,
-}
 
-impl KeenAddons {
-    fn build_ip_geo_addons(
-        input_field_name: &str,
-        output_field_name: &str,
-        remove_ip_property: bool,
-    ) -> Self {
-        KeenAddons {
-            name: "keen:ip_to_geo".to_string(),
-            input: KeenInput {
-                ip: input_field_name.to_string(),
-                remove_ip_property,
-            },
-            output: output_field_name.to_string(),
+    // The events of this collection are sent every send_interval instead of the global interval.
+    // A flush sends them too.
+    pub fn collection_send_interval(mut self, collection: &str, send_interval: Duration) -> Self {
+        self.config.collection_send_intervals.insert(collection.to_owned(), send_interval);
+        self
+    }
+
+    // Drop the events older than max_event_age (keen.timestamp) instead of sending them, e.g.
+    // after a long network outage
+    pub fn max_event_age(mut self, max_event_age: Duration) -> Self {
+        self.config.max_event_age = Some(max_event_age);
+        self
+    }
+
+    // A collection with this number of events is sent right away on its own, so a busy
+    // collection doesn't fill the batches of the other ones
+    pub fn max_events_per_collection(mut self, max_events_per_collection: usize) -> Self {
+        self.config.max_events_per_collection = Some(max_events_per_collection.max(1));
+        self
+    }
+
+    // Randomize each send interval by +/- jitter (0.1 for 10%), so the clients started at the same
+    // time don't send their events at the same time
+    pub fn send_interval_jitter(mut self, jitter: f64) -> Self {
+        self.config.send_interval_jitter = Some(jitter.clamp(0.0, 1.0));
+        self
+    }
+
+    // Same as ProjectSettings::with_request_timeout
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.settings = self.settings.with_request_timeout(request_timeout);
+        self
+    }
+
+    // Retry a failed batch up to max_attempts times, doubling the delay between each attempt
+    pub fn retry(mut self, max_attempts: u32, base_delay: Duration) -> Self {
+        self.config.retry_policy = RetryPolicy::new(max_attempts, base_delay);
+        self
+    }
+
+    // Keep a copy of the buffered events in the directory until they are sent. Events left by a
+    // previous run are sent when the thread starts.
+    pub fn spool_dir<P: Into<PathBuf>>(mut self, spool_dir: P) -> Self {
+        self.config.spool_dir = Some(spool_dir.into());
+        self
+    }
+
+    // Events are sent as soon as this number of events is reached, without waiting for the interval.
+    // 5000 by default.
+    pub fn max_events_per_request(mut self, max_events_per_request: u32) -> Self {
+        self.config.max_events_per_request = max_events_per_request.max(1);
+        self
+    }
+
+    // Send the events as soon as this number of events is waiting, without waiting for the interval.
+    // Lower than max_events_per_request to send the bursts early, the interval is used if not set.
+    pub fn flush_at_count(mut self, flush_at_count: u32) -> Self {
+        self.config.flush_at_count = Some(flush_at_count.max(1));
+        self
+    }
+
+    // Send the events as soon as the waiting events are bigger than this size (serialized json)
+    pub fn flush_at_bytes(mut self, flush_at_bytes: usize) -> Self {
+        self.config.flush_at_bytes = Some(flush_at_bytes);
+        self
+    }
+
+    // Send the events as soon as the oldest waiting event has waited this long, even if the
+    // interval is longer
+    pub fn flush_at_age(mut self, flush_at_age: Duration) -> Self {
+        self.config.flush_at_age = Some(flush_at_age);
+        self
+    }
+
+    // Bigger batches are split in several requests. 1MB by default.
+    pub fn max_request_bytes(mut self, max_request_bytes: usize) -> Self {
+        self.config.max_request_bytes = max_request_bytes;
+        self
+    }
+
+    // Prefix added to all the collection names (ex: "staging_")
+    pub fn collection_prefix(mut self, collection_prefix: &str) -> Self {
+        self.config.collection_prefix = Some(collection_prefix.to_owned());
+        self
+    }
+
+    // Add a unique id (uuid v4) to each event in this property, so the events sent twice (retry,
+    // spool replay) can be deduplicated in the queries. An id already set in the event is kept.
+    pub fn event_id_property(mut self, event_id_property: &str) -> Self {
+        self.config.event_id_property = Some(event_id_property.to_owned());
+        self
+    }
+
+    // Generate an id (uuid v4) for each request sent to Keen, set in this property of its events
+    // and in the X-Request-Id header. It's in the logs of the request, to find the events of a
+    // request in Keen or in the gateway logs.
+    pub fn request_id_property(mut self, request_id_property: &str) -> Self {
+        self.config.request_id_property = Some(request_id_property.to_owned());
+        self
+    }
+
+    // Number the events of each collection (1, 2, 3...) in this property, so the missing or
+    // reordered events can be found in the queries. The sampled events are not numbered.
+    pub fn sequence_property(mut self, sequence_property: &str) -> Self {
+        self.config.sequence_property = Some(sequence_property.to_owned());
+        self
+    }
+
+    // Name of the sending thread, "keen-sender-<project id>" by default
+    pub fn thread_name(mut self, thread_name: &str) -> Self {
+        self.config.thread_name = Some(thread_name.to_owned());
+        self
+    }
+
+    // Log the batches instead of sending them, for tests. Flushes are still notified.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    // Connect to Keen when the thread starts (DNS, TLS handshake) so the first batch doesn't wait
+    // for it. A GET request is sent to the api root, the connection is kept for the batches.
+    pub fn prewarm_connection(mut self, prewarm_connection: bool) -> Self {
+        self.config.prewarm_connection = prewarm_connection;
+        self
+    }
+
+    // Keep the last events in memory instead of sending them, for the hosts that can't reach Keen.
+    // They are read with KeenClient::recent_events (ex: to add them to a support bundle).
+    pub fn ring_buffer(mut self, capacity: usize) -> Self {
+        self.config.ring_buffer = Some(capacity);
+        self
+    }
+
+    // Limit the number of requests sent to Keen. The thread waits before sending, the events
+    // added meanwhile are sent with the next batch.
+    pub fn max_requests_per_second(mut self, max_requests_per_second: f64) -> Self {
+        if max_requests_per_second > 0.0 {
+            self.config.max_requests_per_second = Some(max_requests_per_second);
         }
+        self
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct KeenInput {
-    ip: String,
-    remove_ip_property: bool,
+    // Replace the curl transport, e.g. by a mock in tests
+    pub fn transport<F: Fn() -> Box<dyn Transport> + Send + Sync + 'static>(mut self, transport: F) -> Self {
+        self.config.transport = Some(Arc::new(transport));
+        self
+    }
+
+    // Precision of the keen.timestamp of the events, milliseconds by default
+    pub fn timestamp_format(mut self, timestamp_format: SecondsFormat) -> Self {
+        self.config.timestamp_format = timestamp_format;
+        self
+    }
+
+    // Only queue this fraction (0.0 to 1.0) of the events, chosen randomly. The rate is added in
+    // the keen block (keen.sample_rate) of the events sent.
+    pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+        self.config.sample_rate = Some(sample_rate.clamp(0.0, 1.0));
+        self
+    }
+
+    // Sample rate of a collection, replaces the global one
+    pub fn collection_sample_rate(mut self, collection: &str, sample_rate: f64) -> Self {
+        self.config
+            .collection_sample_rates
+            .insert(collection.to_owned(), sample_rate.clamp(0.0, 1.0));
+        self
+    }
+
+    // Name of the property with the timestamp and the addons, "keen" by default. For the services
+    // emulating Keen with another name.
+    pub fn metadata_key(mut self, metadata_key: &str) -> Self {
+        self.config.metadata_key = metadata_key.to_owned();
+        self
+    }
+
+    // Level of the logs of the sent batches, Trace by default. Off disables them.
+    pub fn success_log_level(mut self, level: LevelFilter) -> Self {
+        self.config.success_log_level = level;
+        self
+    }
+
+    // Level of the logs of the batches that can't be sent and of the events rejected by Keen,
+    // Error by default. Warn (or Off) for the hosts where the analytics are best-effort.
+    pub fn failure_log_level(mut self, level: LevelFilter) -> Self {
+        self.config.failure_log_level = level;
+        self
+    }
+
+    // Time source used by the thread for the send intervals, the system time by default. The
+    // thread waits in real time, KeenClient::tick wakes it up after a mock clock is advanced.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    // Keen reserves the keen property and rejects the names containing a period or starting with $.
+    // By default these events are rejected with Error::ReservedProperty. With sanitize, the
+    // properties are renamed: "keen" (the metadata key) becomes "_keen", "." and a leading "$"
+    // become "_". More "_" are added in front of the new name if the event already has it.
+    pub fn sanitize_properties(mut self, sanitize_properties: bool) -> Self {
+        self.config.sanitize_properties = sanitize_properties;
+        self
+    }
+
+    // Move the properties of the nested objects to the top level of the event, their names joined
+    // with the separator ({"os": {"name": "linux"}} becomes {"os_name": "linux"} with "_").
+    // The arrays are kept as is. A flattened name already used by the event gets "_" in front
+    // of it. The reserved names are checked after flattening ({"keen": {"x": 1}} becomes
+    // "keen.x" with ".", see sanitize_properties).
+    pub fn flatten_properties(mut self, separator: &str) -> Self {
+        self.config.flatten_separator = Some(separator.to_owned());
+        self
+    }
+
+    // Events with more levels of nested objects (the top level properties are level 1) are
+    // rejected with Error::TooDeep instead of being refused by Keen
+    pub fn max_property_depth(mut self, max_property_depth: usize) -> Self {
+        self.config.max_property_depth = Some(max_property_depth);
+        self
+    }
+
+    // Events with more properties (counting the nested ones, not the keen block) are rejected with
+    // Error::TooManyProperties
+    pub fn max_properties(mut self, max_properties: usize) -> Self {
+        self.config.max_properties = Some(max_properties);
+        self
+    }
+
+    // Send this event at each send interval without other events, so a gap in the collection
+    // means the application stopped. Requires a send interval.
+    pub fn heartbeat(mut self, collection: &str, json: &serde_json::Value) -> Self {
+        self.config.heartbeat = Some((collection.to_owned(), json.clone()));
+        self
+    }
+
+    // Send the identical events of a batch (ignoring the keen block and the event id) as one
+    // event, with the number of occurrences in this property
+    pub fn coalesce_events(mut self, count_property: &str) -> Self {
+        self.config.coalesce_property = Some(count_property.to_owned());
+        self
+    }
+
+    // Limit the number of events waiting to be sent
+    pub fn max_queue(mut self, max_queue: usize, policy: BackpressurePolicy) -> Self {
+        self.config.queue_limit = Some(QueueLimit { max_queue, policy });
+        self
+    }
+
+    // Properties added to every event. The properties of the event win over the default ones.
+    pub fn default_properties(mut self, default_properties: serde_json::Value) -> Self {
+        self.config.default_properties = match default_properties {
+            serde_json::Value::Object(properties) => Some(properties),
+            _ => {
+                warn!("Default properties are ignored, they have to be a json object");
+                None
+            }
+        };
+        self
+    }
+
+    // Called from the thread when a batch can't be sent, with the error and the number of dropped events
+    pub fn on_error<F: Fn(&Error, usize) + Send + Sync + 'static>(mut self, on_error: F) -> Self {
+        self.config.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    // Modify (e.g. redact) or drop each event before it's spooled and sent, the heartbeats too.
+    // Returning None drops the event. It's called from the thread, and from the calling thread
+    // for send_event_blocking.
+    pub fn transform<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(&str, serde_json::Value) -> Option<serde_json::Value> + Send + Sync + 'static,
+    {
+        self.config.transform = Some(Arc::new(transform));
+        self
+    }
+
+    // Called from the thread after each request accepted by Keen, with the number of events
+    // accepted in each collection
+    pub fn on_success<F: Fn(&HashMap<String, usize>) + Send + Sync + 'static>(mut self, on_success: F) -> Self {
+        self.config.on_success = Some(Arc::new(on_success));
+        self
+    }
+
+    pub fn build(mut self) -> KeenClient {
+        // The thread knows the collections by their name with the prefix
+        let collection_send_intervals = self.config.collection_send_intervals.drain().collect::<Vec<_>>();
+        for (collection, send_interval) in collection_send_intervals {
+            let collection_name = self.config.collection_name(&collection).unwrap_or(collection);
+            self.config.collection_send_intervals.insert(collection_name, send_interval);
+        }
+
+        KeenClient {
+            settings: Arc::new(Mutex::new(self.settings)),
+            config: self.config,
+            sender: Arc::new(Mutex::new(None)),
+            thread_handle: Arc::new(Mutex::new(None)),
+            queue: Arc::new(QueueState::default()),
+        }
+    }
+}
+
+// KeenClient is Send + Sync and its clones share the same thread and queue. All the methods take
+// &self and can be called concurrently without an external lock, except set_error_callback and
+// clear_error_callback (&mut self, used by the next start). flush(true) and stop block the caller
+// until the thread is done.
+#[derive(Clone)]
+pub struct KeenClient {
+    settings: Arc<Mutex<ProjectSettings>>, // Replaced by update_settings
+    config: ClientConfig,
+    // Keep the sender/receiver in a Mutex because the KeenClient struct has to be sync in DenRouter
+    sender: Arc<Mutex<Option<Sender<Event>>>>,          // Use to send events to the thread
+    thread_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    queue: Arc<QueueState>,
+}
+
+impl KeenClient {
+    pub fn new(settings: ProjectSettings, send_interval: Option<Duration>) -> Self {
+        let builder = KeenClientBuilder::new(settings);
+        match send_interval {
+            Some(send_interval) => builder.send_interval(send_interval).build(),
+            None => builder.build(),
+        }
+    }
+
+    pub fn builder(settings: ProjectSettings) -> KeenClientBuilder {
+        KeenClientBuilder::new(settings)
+    }
+
+    // Number of events dropped because the queue was full
+    pub fn dropped_events(&self) -> usize {
+        self.queue.dropped_events.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> KeenStats {
+        self.queue.stats()
+    }
+
+    // Error of the last batch the thread failed to send, None if the last batch was sent. An
+    // alternative to on_error for the hosts that poll. The error is reported as Error::SendFailed.
+    pub fn last_error(&self) -> Option<Error> {
+        self.queue.last_error.lock().unwrap().clone().map(Error::SendFailed)
+    }
+
+    // Receive the result of each request sent by the thread from now on (also the dry run and ring
+    // buffer ones). Each call returns a new receiver, the results are kept until it's read or dropped.
+    pub fn results_stream(&self) -> Receiver<BatchResult> {
+        let (sender, receiver) = channel();
+        self.queue.results_senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // The events kept by the ring buffer with their collection, the oldest first. Empty without
+    // ring buffer.
+    pub fn recent_events(&self) -> Vec<(String, serde_json::Value)> {
+        self.queue.recent_events.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Same as KeenClientBuilder::on_error, used by the next start
+    pub fn set_error_callback<F: Fn(&Error, usize) + Send + Sync + 'static>(&mut self, on_error: F) {
+        self.config.on_error = Some(Arc::new(on_error));
+    }
+
+    pub fn clear_error_callback(&mut self) {
+        self.config.on_error = None;
+    }
+
+    pub fn start(&self) {
+        let (sender_event, receiver_event) = channel();
+
+        let mut sender_event_opt = self.sender.lock().unwrap();
+        if sender_event_opt.is_none() {
+            let settings = self.settings.lock().unwrap().clone();
+            let config = self.config.clone();
+            let queue = self.queue.clone();
+
+            let thread_name = config
+                .thread_name
+                .clone()
+                .unwrap_or_else(|| format!("keen-sender-{}", settings.project_id));
+
+            let spawn_result = thread::Builder::new().name(thread_name).spawn(move || {
+                send_events_thread(receiver_event, settings, config, queue);
+            });
+
+            match spawn_result {
+                Ok(handle) => {
+                    *sender_event_opt = Some(sender_event);
+                    // The handle is shared with the clones of the client
+                    *self.thread_handle.lock().unwrap() = Some(handle);
+                }
+                Err(e) => error!("Sending thread can't be started: {}", e),
+            }
+        }
+    }
+
+    // Turn the analytics off (ex: the user didn't consent) without stopping the client. While
+    // disabled, the events are ignored, flush returns 0 and the thread doesn't send the events that
+    // were waiting (discard_pending drops them). They are sent when the analytics are enabled again,
+    // or lost if the client is stopped meanwhile (kept in the spool if there is one).
+    pub fn set_enabled(&self, enabled: bool) {
+        let was_disabled = self.queue.disabled.swap(!enabled, Ordering::SeqCst);
+        if enabled && was_disabled {
+            // Wake up the thread, it waits without timeout while disabled
+            if let Some(ref sender) = *self.sender.lock().unwrap() {
+                let _ = sender.send(Event::Resume);
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.queue.is_disabled()
+    }
+
+    // True between start and stop, while the thread is alive
+    pub fn is_running(&self) -> bool {
+        if self.sender.lock().unwrap().is_none() {
+            return false;
+        }
+
+        match *self.thread_handle.lock().unwrap() {
+            Some(ref handle) => !handle.is_finished(),
+            None => false,
+        }
+    }
+
+    // stop and flush only need &self: a clone of the client (they share the thread) can be
+    // given to the thread handling the termination signals to flush or stop on SIGTERM.
+    pub fn stop(&self) {
+        // We drop the sender. The receiver will fail and thread will close.
+        self.sender.lock().unwrap().take();
+        self.queue.notify_space();
+
+        // Wait the end of the thread
+        if let Some(handle) = self.thread_handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+
+    // Same as stop, but wait at most timeout for the thread to send the remaining events.
+    // Return false if the thread didn't finish in time, it's detached and ends on its own. The
+    // flushes waiting meanwhile get their result from the thread, they don't block the stop.
+    pub fn stop_timeout(&self, timeout: Duration) -> bool {
+        self.sender.lock().unwrap().take();
+        self.queue.notify_space();
+
+        let handle = match self.thread_handle.lock().unwrap().take() {
+            Some(handle) => handle,
+            None => return true,
+        };
+
+        // A timeout too big for an Instant (ex: Duration::MAX) waits for the thread
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => {
+                let _ = handle.join();
+                return true;
+            }
+        };
+        while !handle.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("Thread didn't stop in {:?}, remaining events may not be sent", timeout);
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let _ = handle.join();
+        true
+    }
+
+    // Return the number of events sent by the flush. Always 0 if we don't wait for the flush.
+    // When waiting, Error::SendFailed is returned if the flushed events can't be sent.
+    // flush(true) is a barrier: it returns when all the events added before the call are sent.
+    // The thread receives them before the flush in the order they were added, the batches
+    // triggered by the count limits are sent before it, and the flush sends all the waiting events
+    // (even the collections with their own interval) in as many requests as needed.
+    pub fn flush(&self, wait: bool) -> Result<usize, Error> {
+        let events_sent = self.send_flush(wait, None)?;
+        Ok(events_sent.unwrap_or(0))
+    }
+
+    // Wait the end of the flush at most timeout. Return false if the flush is not done in time.
+    pub fn flush_timeout(&self, timeout: Duration) -> Result<bool, Error> {
+        let events_sent = self.send_flush(true, Some(timeout))?;
+        Ok(events_sent.is_some())
+    }
+
+    fn send_flush(&self, wait: bool, timeout: Option<Duration>) -> Result<Option<usize>, Error> {
+        if self.queue.is_disabled() {
+            return Ok(Some(0));
+        }
+
+        // Send the event FLUSH. The sender is not locked while waiting, so events can be added.
+        let sender = self.sender.lock().unwrap().clone();
+
+        if let Some(ref sender) = sender {
+            // Each waiting flush has its own channel, the concurrent flushes don't take the
+            // result of each other and a flush that timed out only drops its own
+            if !wait {
+                sender.send(Event::Flush(None)).map_err(|e| Error::Io(e.to_string()))?;
+                return Ok(Some(0));
+            }
+            let (result_sender, result_receiver) = channel();
+            sender.send(Event::Flush(Some(result_sender))).map_err(|e| Error::Io(e.to_string()))?;
+            wait_flush(&result_receiver, timeout)
+        } else {
+            Err(Error::NotStarted)
+        }
+    }
+
+    // Same as flush(true) without blocking the caller: the returned future completes when the
+    // thread has sent the events. It doesn't depend on any async runtime, the events are still
+    // sent with curl by the thread of the client.
+    pub fn flush_async(&self) -> FlushFuture {
+        let (future, completer) = FlushFuture::new();
+        if self.queue.is_disabled() {
+            completer.complete(Ok(0));
+            return future;
+        }
+
+        let sender = self.sender.lock().unwrap();
+        match *sender {
+            // If the thread is gone, the completer is dropped with the event and the future fails
+            Some(ref sender) => {
+                let _ = sender.send(Event::AsyncFlush(completer));
+            }
+            None => completer.complete(Err(Error::NotStarted)),
+        }
+
+        future
+    }
+
+    // Wake up the thread so it checks the send intervals and the age of the events against the
+    // clock now. Only needed with a mock clock (KeenClientBuilder::clock) that was advanced, the
+    // thread waits in real time for the next interval.
+    pub fn tick(&self) {
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            let _ = sender.send(Event::Tick);
+        }
+    }
+
+    // Drop the events waiting to be sent (and the spooled ones) without sending them, e.g. when
+    // the user opts out. The events added after this call are sent normally.
+    pub fn discard_pending(&self) -> Result<(), Error> {
+        match *self.sender.lock().unwrap() {
+            Some(ref sender) => sender.send(Event::Discard).map_err(|e| Error::Io(e.to_string())),
+            None => Err(Error::NotStarted),
+        }
+    }
+
+    // The settings used by the next requests
+    pub fn settings(&self) -> ProjectSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    // Replace the settings (ex: a rotated api key) without stopping the thread. The queued events
+    // and the next ones are sent with the new settings, the events of a batch being sent are not.
+    pub fn update_settings(&self, settings: ProjectSettings) -> Result<(), Error> {
+        *self.settings.lock().unwrap() = settings.clone();
+        match *self.sender.lock().unwrap() {
+            Some(ref sender) => sender
+                .send(Event::UpdateSettings(Box::new(settings)))
+                .map_err(|e| Error::Io(e.to_string())),
+            // Used by the next start
+            None => Ok(()),
+        }
+    }
+
+    // Return the approximate number of events waiting to be sent, including this one
+    pub fn add_event(&self, collection: &str, json: &serde_json::Value) -> Result<usize, Error> {
+        self.add_event_with_param(collection, Cow::Borrowed(json), Vec::new(), Utc::now())
+    }
+
+    // Same as add_event with a collection defined by keen_collections! (or any AsRef<str>), so
+    // a typo in the collection name doesn't compile
+    pub fn add_event_into<C: AsRef<str>>(&self, collection: C, json: &serde_json::Value) -> Result<usize, Error> {
+        self.add_event(collection.as_ref(), json)
+    }
+
+    // The event has to serialize to a json object
+    pub fn add_event_typed<T: Serialize>(&self, collection: &str, event: &T) -> Result<usize, Error> {
+        let json = serde_json::to_value(event)?;
+        if !json.is_object() {
+            return Err(Error::InvalidEventShape);
+        }
+
+        self.add_event(collection, &json)
+    }
+
+    // Convenience for the events already serialized to a json object (ex: json.to_string()), like
+    // add_event after parsing the text. It doesn't save the parse and the serialization of the
+    // batch: the thread needs the properties (keen block, transform, coalescing, spool).
+    pub fn add_event_raw(&self, collection: &str, json: &str) -> Result<usize, Error> {
+        let json: serde_json::Value = serde_json::from_str(json)?;
+        self.add_event_with_param(collection, Cow::Owned(json), Vec::new(), Utc::now())
+    }
+
+    // Use the timestamp instead of now, for events collected earlier (ex: while offline)
+    pub fn add_event_with_timestamp(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+        timestamp: DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        self.add_event_with_param(collection, Cow::Borrowed(json), Vec::new(), timestamp)
+    }
+
+    pub fn add_event_with_geo_enrichment(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        self.add_event_with_geo_enrichment_fields(collection, json, "ip_address", "ip_geo_info", true)
+    }
+
+    // Geo information of the ip_field_name property in the output_field_name property. With
+    // ip_placeholder, the property is set to the ip of the request (and removed by Keen),
+    // otherwise the event has a captured ip in the property.
+    pub fn add_event_with_geo_enrichment_fields(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+        ip_field_name: &str,
+        output_field_name: &str,
+        ip_placeholder: bool,
+    ) -> Result<usize, Error> {
+        let addon = if ip_placeholder {
+            KeenAddons::build_ip_geo_addons(ip_field_name, output_field_name, true)
+        } else {
+            KeenAddons::build_ip_geo_addons_from_property(ip_field_name, output_field_name, false)
+        };
+        self.add_event_with_param(collection, Cow::Borrowed(json), vec![addon], Utc::now())
+    }
+
+    // Expand the keen timestamp into day of week, hour, month... in the timestamp_info property
+    pub fn add_event_with_datetime_parsing(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        let timestamp = format!("{}.timestamp", self.config.metadata_key);
+        let addon = KeenAddons::build_datetime_parser(&timestamp, "timestamp_info");
+        self.add_event_with_param(collection, Cow::Borrowed(json), vec![addon], Utc::now())
+    }
+
+    // Expand the user_agent property of the event into browser, os and device in the
+    // parsed_user_agent property
+    pub fn add_event_with_ua_parsing(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        let addon = KeenAddons::build_ua_parser("user_agent", "parsed_user_agent");
+        self.add_event_with_param(collection, Cow::Borrowed(json), vec![addon], Utc::now())
+    }
+
+    // Split the page_url property of the event into protocol, domain, path... in the
+    // parsed_page_url property
+    pub fn add_event_with_url_parsing(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        let addon = KeenAddons::build_url_parser("page_url", "parsed_page_url");
+        self.add_event_with_param(collection, Cow::Borrowed(json), vec![addon], Utc::now())
+    }
+
+    // Find the source (search, social...) of the referrer_url property of the event, landing on
+    // page_url, in the referrer_info property
+    pub fn add_event_with_referrer_parsing(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        let addon = KeenAddons::build_referrer_parser("referrer_url", "page_url", "referrer_info");
+        self.add_event_with_param(collection, Cow::Borrowed(json), vec![addon], Utc::now())
+    }
+
+    // Any combination of addons can be used. The ${keen.ip} placeholder is added for the
+    // input of the ip to geo addons, except the ones built with build_ip_geo_addons_from_property.
+    pub fn add_event_with_addons(
+        &self,
+        collection: &str,
+        json: &serde_json::Value,
+        addons: Vec<KeenAddons>,
+    ) -> Result<usize, Error> {
+        self.add_event_with_param(collection, Cow::Borrowed(json), addons, Utc::now())
+    }
+
+    fn add_event_with_param(
+        &self,
+        collection: &str,
+        json: Cow<serde_json::Value>,
+        addons: Vec<KeenAddons>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        if self.queue.is_disabled() {
+            return Ok(self.queue.pending());
+        }
+        if !json.is_object() {
+            return Err(Error::InvalidEventShape);
+        }
+
+        let collection_name = self.collection_name(collection)?;
+        let sample_rate = self.sample_rate(collection);
+        if !self.is_sampled(sample_rate) {
+            return Ok(self.queue.pending());
+        }
+
+        let mut json = self.build_event(json.into_owned(), addons, timestamp)?;
+        self.set_sample_rate(&mut json, sample_rate);
+        self.set_sequence_numbers(&collection_name, std::slice::from_mut(&mut json));
+
+        let event = Event::KeenEvent(collection_name, json);
+        self.queue_event(event, 1)
+    }
+
+    // False if the event is skipped by the sampling, counted in the stats
+    fn is_sampled(&self, sample_rate: Option<f64>) -> bool {
+        match sample_rate {
+            Some(sample_rate) if rand::random::<f64>() >= sample_rate => {
+                self.queue.sampled_events.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Keep the rate in the keen block so the queries can scale the counts
+    fn set_sample_rate(&self, json: &mut serde_json::Value, sample_rate: Option<f64>) {
+        if let Some(sample_rate) = sample_rate {
+            json[self.config.metadata_key.as_str()]["sample_rate"] = json!(sample_rate);
+        }
+    }
+
+    // None if all the events of the collection are kept
+    fn sample_rate(&self, collection: &str) -> Option<f64> {
+        self.config
+            .collection_sample_rates
+            .get(collection)
+            .cloned()
+            .or(self.config.sample_rate)
+            .filter(|&sample_rate| sample_rate < 1.0)
+    }
+
+    // Send all the events to the thread at once
+    pub fn add_events(&self, collection: &str, jsons: &[serde_json::Value]) -> Result<usize, Error> {
+        if jsons.is_empty() || self.queue.is_disabled() {
+            return Ok(self.queue.pending());
+        }
+
+        let collection_name = self.collection_name(collection)?;
+        let sample_rate = self.sample_rate(collection);
+        let now = Utc::now();
+        let mut events = Vec::with_capacity(jsons.len());
+        for json in jsons {
+            if self.is_sampled(sample_rate) {
+                let mut event = self.build_event(json.clone(), Vec::new(), now)?;
+                self.set_sample_rate(&mut event, sample_rate);
+                events.push(event);
+            }
+        }
+        if events.is_empty() {
+            return Ok(self.queue.pending());
+        }
+
+        self.set_sequence_numbers(&collection_name, &mut events);
+        let events_qty = events.len();
+        self.queue_event(Event::KeenEvents(collection_name, events), events_qty)
+    }
+
+    // Set the next numbers of the collection in sequence_property
+    fn set_sequence_numbers(&self, collection_name: &str, events: &mut [serde_json::Value]) {
+        let sequence_property = match self.config.sequence_property {
+            Some(ref sequence_property) => sequence_property,
+            None => return,
+        };
+
+        let mut sequence_numbers = self.queue.sequence_numbers.lock().unwrap();
+        let sequence_number = sequence_numbers.entry(collection_name.to_owned()).or_insert(0);
+        for event in events {
+            if let Some(object) = event.as_object_mut() {
+                *sequence_number += 1;
+                object.insert(sequence_property.clone(), json!(*sequence_number));
+            }
+        }
+    }
+
+    // Send the event to another project than the one of the client. The events of all the
+    // projects are sent by the same thread. They are not kept in the spool.
+    pub fn add_event_to(
+        &self,
+        settings: &ProjectSettings,
+        collection: &str,
+        json: &serde_json::Value,
+    ) -> Result<usize, Error> {
+        if self.queue.is_disabled() {
+            return Ok(self.queue.pending());
+        }
+
+        let collection_name = self.collection_name(collection)?;
+        let sample_rate = self.sample_rate(collection);
+        if !self.is_sampled(sample_rate) {
+            return Ok(self.queue.pending());
+        }
+
+        let mut json = self.build_event(json.clone(), Vec::new(), Utc::now())?;
+        self.set_sample_rate(&mut json, sample_rate);
+        let event = Event::ProjectEvent(Box::new(settings.clone()), collection_name, json);
+        self.queue_event(event, 1)
+    }
+
+    // events_qty is the number of events in the event
+    fn queue_event(&self, event: Event, events_qty: usize) -> Result<usize, Error> {
+        if let Some(queue_limit) = self.config.queue_limit {
+            match queue_limit.policy {
+                BackpressurePolicy::DropNewest if self.queue.pending() + events_qty > queue_limit.max_queue => {
+                    self.queue.events_dropped(events_qty);
+                    return Err(Error::QueueFull);
+                }
+                BackpressurePolicy::Block => self.wait_queue_space(events_qty, queue_limit.max_queue)?,
+                BackpressurePolicy::DropOldest => return self.queue_dropping_oldest(event, events_qty, queue_limit.max_queue),
+                _ => {}
+            }
+        }
+
+        // Send the event
+        let sender = self.sender.lock().unwrap();
+        if let Some(ref sender) = *sender {
+            // Count the event before sending it, the thread could send it before we get here
+            let pending_events = self.queue.add(events_qty);
+            sender.send(event).map(|_| {
+                self.queue.accepted_events.fetch_add(events_qty, Ordering::SeqCst);
+                metrics_recorder::events_accepted(events_qty);
+                pending_events
+            }).map_err(|e| {
+                self.queue.remove(events_qty);
+                Error::Io(e.to_string())
+            })
+        } else {
+            Err(Error::NotStarted)
+        }
+    }
+
+    // The events wait in waiting_events instead of the channel so the oldest ones can be dropped
+    // here, the queue stays under its limit while the thread is busy sending. Only the first
+    // waiting event wakes up the thread, it takes them all.
+    fn queue_dropping_oldest(&self, event: Event, events_qty: usize, max_queue: usize) -> Result<usize, Error> {
+        let sender = self.sender.lock().unwrap();
+        let sender = match *sender {
+            Some(ref sender) => sender,
+            None => return Err(Error::NotStarted),
+        };
+
+        let mut waiting_events = self.queue.waiting_events.lock().unwrap();
+        let was_empty = waiting_events.is_empty();
+        let mut pending_events = self.queue.add(events_qty);
+        waiting_events.push_back(event);
+        self.queue.accepted_events.fetch_add(events_qty, Ordering::SeqCst);
+        metrics_recorder::events_accepted(events_qty);
+
+        // The events already received by the thread can't be dropped, the new event is dropped
+        // if they fill the queue
+        let mut dropped_qty = 0;
+        while pending_events - dropped_qty > max_queue {
+            let over_qty = pending_events - dropped_qty - max_queue;
+            match waiting_events.front_mut() {
+                Some(&mut Event::KeenEvents(_, ref mut jsons)) if jsons.len() > over_qty => {
+                    jsons.drain(..over_qty);
+                    dropped_qty += over_qty;
+                    continue;
+                }
+                Some(&mut Event::KeenEvents(_, ref jsons)) => dropped_qty += jsons.len(),
+                Some(_) => dropped_qty += 1,
+                None => break,
+            }
+            waiting_events.pop_front();
+        }
+        if dropped_qty > 0 {
+            self.queue.remove(dropped_qty);
+            self.queue.events_dropped(dropped_qty);
+            pending_events -= dropped_qty;
+        }
+
+        if was_empty && !waiting_events.is_empty() {
+            sender.send(Event::Waiting).map_err(|e| Error::Io(e.to_string()))?;
+        }
+        Ok(pending_events)
+    }
+
+    // Send the event from the calling thread and return the result of the request, an error if
+    // Keen rejected the event. It doesn't need the thread to be started. Nothing is sent while
+    // the client is disabled. The request is counted in the stats and reported to the callbacks
+    // like a batch of the thread.
+    pub fn send_event_blocking(&self, collection: &str, json: &serde_json::Value) -> Result<(), Error> {
+        if self.queue.is_disabled() {
+            return Ok(());
+        }
+
+        let collection = self.collection_name(collection)?;
+        let event = self.build_event(json.clone(), Vec::new(), Utc::now())?;
+
+        let settings = self.settings.lock().unwrap().clone();
+        send_event_now(&settings, &self.config, collection, event, &self.queue)
+    }
+
+    // Same as send_event_blocking, returns Error::Timeout if Keen hasn't accepted the event
+    // before the timeout (ex: installer telemetry). The request runs in its own thread so a
+    // hanging connection doesn't block the caller. Each attempt of the request is limited to the
+    // timeout, the thread ends after the retries.
+    pub fn send_event_blocking_timeout(&self, collection: &str, json: &serde_json::Value, timeout: Duration) -> Result<(), Error> {
+        if self.queue.is_disabled() {
+            return Ok(());
+        }
+
+        let collection = self.collection_name(collection)?;
+        let event = self.build_event(json.clone(), Vec::new(), Utc::now())?;
+
+        let settings = self.settings.lock().unwrap().clone().with_request_timeout(timeout);
+        let config = self.config.clone();
+        let queue = self.queue.clone();
+        let (sender, receiver) = channel();
+        thread::Builder::new()
+            .name("keen-send-event".to_string())
+            .spawn(move || {
+                let _ = sender.send(send_event_now(&settings, &config, collection, event, &queue));
+            })?;
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(e) => Err(Error::Io(e.to_string())),
+        }
+    }
+
+    // Run an analysis (count, sum, average...) on a collection with the read key of the project.
+    // The timeframe is relative ("this_7_days") or absolute (json with start and end).
+    pub fn query(&self, collection: &str, analysis_type: &str, timeframe: &str) -> Result<serde_json::Value, Error> {
+        let settings = self.settings.lock().unwrap().clone();
+        let read_key = settings.read_key.as_ref().ok_or(Error::MissingReadKey)?;
+
+        let path = format!("queries/{}", analysis_type);
+        let params = [("event_collection", collection), ("timeframe", timeframe)];
+        let response = get_from_keen(&mut *self.config.new_transport(), &settings, &path, read_key, &params)?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    fn collection_name(&self, collection: &str) -> Result<String, Error> {
+        self.config.collection_name(collection)
+    }
+
+    fn build_event(&self, mut json: serde_json::Value, addons: Vec<KeenAddons>, timestamp: DateTime<Utc>) -> Result<serde_json::Value, Error> {
+        // The events that are not objects (array, number...) would be refused by Keen
+        let object = json.as_object_mut().ok_or(Error::InvalidEventShape)?;
+        if let Some(ref separator) = self.config.flatten_separator {
+            let nested_object = mem::replace(object, serde_json::Map::new());
+            flatten_properties(None, nested_object, separator, object);
+        }
+        check_property_names(object, self.config.sanitize_properties, Some(&self.config.metadata_key))?;
+
+        if let Some(ref default_properties) = self.config.default_properties {
+            for (key, value) in default_properties {
+                if !object.contains_key(key) {
+                    object.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        if let Some(ref event_id_property) = self.config.event_id_property {
+            if !object.contains_key(event_id_property) {
+                object.insert(event_id_property.clone(), json!(Uuid::new_v4().to_string()));
+            }
+        }
+
+        if let Some(max_property_depth) = self.config.max_property_depth {
+            let depth = object_depth(object);
+            if depth > max_property_depth {
+                return Err(Error::TooDeep(depth));
+            }
+        }
+        if let Some(max_properties) = self.config.max_properties {
+            let count = property_count(object);
+            if count > max_properties {
+                return Err(Error::TooManyProperties(count));
+            }
+        }
+
+        // Add a timestamp
+        let mut keen_info =
+            KeenInfo::new(timestamp.to_rfc3339_opts(self.config.timestamp_format, true));
+        for addon in addons {
+            if let KeenInput::IpToGeo { ref ip, ip_placeholder: true, .. } = addon.input {
+                object.insert(ip.clone(), json!("${keen.ip}"));
+            }
+            keen_info.add_addon(addon);
+        }
+
+        object.insert(
+            self.config.metadata_key.clone(),
+            serde_json::to_value(&keen_info)?,
+        );
+
+        Ok(json)
+    }
+
+    // The thread is told to send the buffered events, they could otherwise wait for a count or
+    // an interval the full queue never reaches. The events are removed from the queue when
+    // they are sent and stop wakes up the callers too. A batch bigger than max_queue waits
+    // for an empty queue.
+    fn wait_queue_space(&self, events_qty: usize, max_queue: usize) -> Result<(), Error> {
+        let mut flush_sent = false;
+        loop {
+            // The sender is locked before space_lock like in queue_event, and space_lock is taken
+            // before releasing it so a stop can't be missed
+            let sender = self.sender.lock().unwrap();
+            let guard = self.queue.space_lock.lock().unwrap();
+            let pending_events = self.queue.pending();
+            if pending_events == 0 || pending_events + events_qty <= max_queue {
+                return Ok(());
+            }
+
+            // Don't wait forever if the thread is stopped
+            match *sender {
+                Some(ref sender) if !flush_sent => {
+                    sender.send(Event::Flush(None)).map_err(|e| Error::Io(e.to_string()))?;
+                    flush_sent = true;
+                }
+                Some(_) => {}
+                None => return Err(Error::NotStarted),
+            }
+            drop(sender);
+            drop(self.queue.space_available.wait(guard).unwrap());
+        }
+    }
+}
+
+// When the last clone of a started client is dropped, the remaining events are sent like with
+// stop_timeout. Nothing is done during a panic to avoid panicking again.
+impl Drop for KeenClient {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.sender) > 1 || thread::panicking() {
+            return;
+        }
+
+        let running = self.sender.lock().map(|sender| sender.is_some()).unwrap_or(false);
+        if running {
+            self.stop_timeout(DROP_STOP_TIMEOUT);
+        }
+    }
+}
+
+// Insert the properties of object in flat_object, the nested objects are replaced by their
+// properties prefixed with the name of the object. The joined names can be the same as another
+// property ({"os_name": 1, "os": {"name": 2}} with "_"): the plain properties are inserted first
+// and keep their name, "_" is added in front of the flattened one.
+fn flatten_properties(
+    prefix: Option<&str>,
+    object: serde_json::Map<String, serde_json::Value>,
+    separator: &str,
+    flat_object: &mut serde_json::Map<String, serde_json::Value>,
+) {
+    let (nested_objects, values): (Vec<_>, Vec<_>) = object.into_iter().partition(|(_, value)| value.is_object());
+    for (name, value) in values.into_iter().chain(nested_objects) {
+        let mut name = match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, separator, name),
+            None => name,
+        };
+        match value {
+            serde_json::Value::Object(nested_object) => flatten_properties(Some(&name), nested_object, separator, flat_object),
+            value => {
+                while flat_object.contains_key(&name) {
+                    name.insert(0, '_');
+                }
+                flat_object.insert(name, value);
+            }
+        }
+    }
+}
+
+// Levels of nested objects, the arrays don't add a level
+fn object_depth(object: &serde_json::Map<String, serde_json::Value>) -> usize {
+    1 + object.values().map(value_depth).max().unwrap_or(0)
+}
+
+fn value_depth(value: &serde_json::Value) -> usize {
+    match *value {
+        serde_json::Value::Object(ref object) => object_depth(object),
+        serde_json::Value::Array(ref values) => values.iter().map(value_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// Properties of the object and of the nested objects (also in the arrays)
+fn property_count(object: &serde_json::Map<String, serde_json::Value>) -> usize {
+    object.len() + object.values().map(nested_property_count).sum::<usize>()
+}
+
+fn nested_property_count(value: &serde_json::Value) -> usize {
+    match *value {
+        serde_json::Value::Object(ref object) => property_count(object),
+        serde_json::Value::Array(ref values) => values.iter().map(nested_property_count).sum(),
+        _ => 0,
+    }
+}
+
+// Check the property names of the object and of the nested objects. The metadata key (keen) is
+// only reserved at the top level.
+fn check_property_names(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    sanitize: bool,
+    metadata_key: Option<&str>,
+) -> Result<(), Error> {
+    let invalid_names: Vec<String> = object
+        .keys()
+        .filter(|name| Some(name.as_str()) == metadata_key || name.contains('.') || name.starts_with('$'))
+        .cloned()
+        .collect();
+
+    for name in invalid_names {
+        if !sanitize {
+            return Err(Error::ReservedProperty(name));
+        }
+
+        if let Some(value) = object.remove(&name) {
+            let mut sanitized_name = if Some(name.as_str()) == metadata_key {
+                format!("_{}", name)
+            } else {
+                name.replace('.', "_")
+            };
+            if sanitized_name.starts_with('$') {
+                sanitized_name.replace_range(..1, "_");
+            }
+            // Don't overwrite an existing property
+            while object.contains_key(&sanitized_name) {
+                sanitized_name.insert(0, '_');
+            }
+            object.insert(sanitized_name, value);
+        }
+    }
+
+    for value in object.values_mut() {
+        check_nested_property_names(value, sanitize)?;
+    }
+
+    Ok(())
+}
+
+fn check_nested_property_names(value: &mut serde_json::Value, sanitize: bool) -> Result<(), Error> {
+    match *value {
+        serde_json::Value::Object(ref mut object) => check_property_names(object, sanitize, None),
+        serde_json::Value::Array(ref mut values) => {
+            for value in values {
+                check_nested_property_names(value, sanitize)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_collection_name(collection: &str) -> Result<(), Error> {
+    let problem = if collection.is_empty() {
+        "it is empty"
+    } else if collection.len() > MAX_COLLECTION_NAME_LENGTH {
+        "it is longer than 256 characters"
+    } else if !collection.is_ascii() {
+        "it contains non-ASCII characters"
+    } else if collection.contains('$') {
+        "it contains the $ character"
+    } else if collection.starts_with('_') {
+        "it starts with an underscore"
+    } else if collection.starts_with('.') {
+        "it starts with a period"
+    } else if collection.trim() != collection {
+        "it starts or ends with whitespaces"
+    } else if collection.chars().any(|c| c.is_control()) {
+        "it contains control characters"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::InvalidCollection(format!("\"{}\": {}", collection, problem)))
+}
+
+// Return None if the flush is not done before the timeout. A timeout too big for an Instant
+// waits without timeout.
+fn wait_flush(result_receiver: &Receiver<FlushResult>, timeout: Option<Duration>) -> Result<Option<usize>, Error> {
+    let flush_result = match timeout.and_then(|timeout| Instant::now().checked_add(timeout)) {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match result_receiver.recv_timeout(remaining) {
+                Ok(flush_result) => flush_result,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(e) => return Err(Error::Io(e.to_string())),
+            }
+        }
+        None => result_receiver.recv().map_err(|e| Error::Io(e.to_string()))?,
+    };
+    flush_result.map(Some).map_err(Error::SendFailed)
+}
+
+fn send_events_thread(
+    receiver: Receiver<Event>,
+    mut settings: ProjectSettings,
+    config: ClientConfig,
+    queue: Arc<QueueState>,
+) {
+    let mut send_events = false; // The global interval is elapsed
+    let mut flush_events = false;
+    let mut notify_callers = Vec::new();
+    let mut async_flushes = Vec::new();
+    let mut events_qty = 0u32;
+    let mut events: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    // Events taken from queue.waiting_events, received before the channel
+    let mut waiting_events = VecDeque::new();
+    // Events added with add_event_to, by project
+    let mut project_events: HashMap<ProjectKey, (ProjectSettings, HashMap<String, Vec<serde_json::Value>>)> = HashMap::new();
+    let mut stop_thread = false;
+    // Size and age of the waiting events, for flush_at_bytes and flush_at_age
+    let mut buffered_bytes = 0;
+    let mut oldest_event = None;
+    let mut now = config.clock.now();
+    // Last send of the collections with their own interval
+    let mut collection_sends = HashMap::new();
+    let thread_start = now;
+    let mut send_interval = config.send_interval.map(|interval| jittered_interval(interval, config.send_interval_jitter));
+    // Reused for all the requests to keep the connection alive
+    let mut transport = config.new_transport();
+    if config.prewarm_connection && !config.dry_run && config.ring_buffer.is_none() && !queue.is_disabled() {
+        prewarm_connection(&mut *transport, &settings);
+    }
+
+    // Replay the events that were not sent by a previous run
+    let mut spool = None;
+    if let Some(ref spool_dir) = config.spool_dir {
+        match EventSpool::open(spool_dir) {
+            Ok((event_spool, spooled_events)) => {
+                spool = Some(event_spool);
+                if !spooled_events.is_empty() {
+                    trace!("Replaying {} spooled events", spooled_events.len());
+                    queue.add(spooled_events.len());
+                    for (collection, json) in spooled_events {
+                        events.entry(collection).or_default().push(json);
+                    }
+                    if !queue.is_disabled() {
+                        let _ = send_batch(&mut *transport, &settings, &config, &mut events, &mut spool, &queue);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Event spool can't be opened in {}: {}", spool_dir.display(), e);
+            }
+        }
+    }
+
+    loop {
+        // Calculate next timeout before sending events
+        let global_timeout = send_interval.map(|interval| remaining_time(&*config.clock, now, interval));
+        if global_timeout.is_none() {
+            // Without interval, events are sent as soon as they are received
+            send_events = true;
+        }
+
+        let collection_timeout = config
+            .collection_send_intervals
+            .iter()
+            .filter(|&(collection, _)| events.contains_key(collection))
+            .map(|(collection, &interval)| remaining_time(&*config.clock, *collection_sends.get(collection).unwrap_or(&thread_start), interval))
+            .min();
+
+        let age_timeout = match (oldest_event, config.flush_at_age) {
+            (Some(oldest_event), Some(flush_at_age)) => Some(remaining_time(&*config.clock, oldest_event, flush_at_age)),
+            _ => None,
+        };
+
+        // Nothing is sent while disabled, set_enabled(true) wakes up the thread
+        let timeout = if queue.is_disabled() {
+            None
+        } else {
+            [global_timeout, collection_timeout, age_timeout].iter().flatten().min().cloned()
+        };
+        // The waiting events taken are received before the next events of the channel
+        let received = match (waiting_events.pop_front(), timeout) {
+            (Some(event), _) => Ok(event),
+            (None, Some(timeout)) => receiver.recv_timeout(timeout),
+            (None, None) => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(Event::Waiting) => waiting_events.extend(queue.waiting_events.lock().unwrap().drain(..)),
+            Ok(Event::KeenEvent(collection, json)) => {
+                if let Some(json) = transform_event(&config, &queue, &collection, json) {
+                    spool_event(&mut spool, &collection, &json);
+                    events_qty += 1;
+                    buffered_bytes += buffered_event_bytes(&config, &json);
+                    let collection = events.entry(collection).or_default();
+                    collection.push(json);
+                }
+            }
+            Ok(Event::KeenEvents(collection, jsons)) => {
+                let jsons: Vec<_> = jsons
+                    .into_iter()
+                    .filter_map(|json| transform_event(&config, &queue, &collection, json))
+                    .collect();
+                if !jsons.is_empty() {
+                    for json in &jsons {
+                        spool_event(&mut spool, &collection, json);
+                        buffered_bytes += buffered_event_bytes(&config, json);
+                    }
+                    events_qty += jsons.len() as u32;
+                    events.entry(collection).or_default().extend(jsons);
+                }
+            }
+            Ok(Event::ProjectEvent(project_settings, collection, json)) => {
+                if let Some(json) = transform_event(&config, &queue, &collection, json) {
+                    events_qty += 1;
+                    buffered_bytes += buffered_event_bytes(&config, &json);
+                    let project = project_events
+                        .entry(project_settings.key())
+                        .or_insert_with(|| (*project_settings, HashMap::new()));
+                    project.1.entry(collection).or_default().push(json);
+                }
+            }
+            Ok(Event::Flush(notify)) => {
+                // All the events added before the flush are already received
+                flush_events = true;
+                notify_callers.extend(notify);
+            }
+            Ok(Event::AsyncFlush(completer)) => {
+                flush_events = true;
+                async_flushes.push(completer);
+            }
+            Ok(Event::Discard) => {
+                let discarded_qty = buffered_events_qty(&events, &project_events);
+                events.clear();
+                project_events.clear();
+                events_qty = 0;
+                buffered_bytes = 0;
+                oldest_event = None;
+                queue.remove(discarded_qty);
+                if let Some(ref mut spool) = spool {
+                    if let Err(e) = spool.clear() {
+                        error!("Event spool can't be cleared: {}", e);
+                    }
+                }
+                trace!("{} pending events discarded", discarded_qty);
+            }
+            Ok(Event::Resume) => {}
+            Ok(Event::UpdateSettings(new_settings)) => {
+                trace!("Settings updated: {:?}", new_settings);
+                settings = *new_settings;
+            }
+            Ok(Event::Tick) | Err(RecvTimeoutError::Timeout) => {
+                // It can be the timeout of a collection interval
+                if send_interval.is_none_or(|interval| remaining_time(&*config.clock, now, interval) == Duration::from_millis(0)) {
+                    send_events = true;
+                    if events.is_empty() {
+                        add_heartbeat(&config, &mut events, &queue);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                stop_thread = true;
+            }
+        }
+
+        if events_qty == 0 {
+            oldest_event = None;
+        } else if oldest_event.is_none() {
+            oldest_event = Some(config.clock.now());
+        }
+
+        let buffer = BufferState {
+            flush_requested: flush_events,
+            stopping: stop_thread,
+            events_qty,
+            buffered_bytes,
+            oldest_event_age: oldest_event.map(|oldest_event| elapsed_since(&*config.clock, oldest_event)),
+            interval_elapsed: send_events,
+            collection_due: config.collection_send_intervals.iter().any(|(collection, &interval)| {
+                events.contains_key(collection)
+                    && remaining_time(&*config.clock, *collection_sends.get(collection).unwrap_or(&thread_start), interval) == Duration::from_millis(0)
+            }),
+            collection_full: config.max_events_per_collection.is_some_and(|max_events| {
+                events.values().any(|collection_events| collection_events.len() >= max_events)
+            }),
+        };
+        let flush_reason = if queue.is_disabled() { None } else { flush_reason(&config, &buffer) };
+        if let Some(reason) = flush_reason {
+            trace!("Sending the waiting events: {:?}", reason);
+            queue.set_last_flush_reason(reason);
+        }
+
+        let mut batch_result = Ok(0);
+        if flush_reason == Some(FlushReason::CollectionFull) || flush_reason == Some(FlushReason::CollectionInterval) {
+            // Only the collections that are full or with their own interval that is elapsed
+            let mut held_events = hold_collections(&config, &mut events, false, &collection_sends, thread_start);
+            batch_result = send_collections(&mut *transport, &settings, &config, &mut events, &mut held_events, &mut spool, &queue, &mut collection_sends);
+            events = held_events;
+            events_qty = buffered_events_qty(&events, &project_events) as u32;
+            buffered_bytes = buffered_events_bytes(&config, &events, &project_events);
+        } else if let Some(reason) = flush_reason {
+            // Everything is sent, except the collections with their own interval when the global
+            // interval is elapsed
+            let send_all = reason != FlushReason::Interval;
+            now = config.clock.now();
+            send_interval = config.send_interval.map(|interval| jittered_interval(interval, config.send_interval_jitter));
+
+            // The collections with their own interval wait for it, unless everything is flushed
+            let mut held_events = if send_all {
+                HashMap::new()
+            } else {
+                hold_collections(&config, &mut events, true, &collection_sends, thread_start)
+            };
+            batch_result = send_collections(&mut *transport, &settings, &config, &mut events, &mut held_events, &mut spool, &queue, &mut collection_sends);
+            events = held_events;
+
+            for (_, &mut (ref project_settings, ref mut project_collections)) in project_events.iter_mut() {
+                let project_result = send_batch(&mut *transport, project_settings, &config, project_collections, &mut None, &queue);
+                batch_result = match (batch_result, project_result) {
+                    (Ok(events_sent), Ok(project_events_sent)) => Ok(events_sent + project_events_sent),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                };
+            }
+            project_events.retain(|_, &mut (_, ref project_collections)| !project_collections.is_empty());
+            send_events = false;
+            flush_events = false;
+            events_qty = buffered_events_qty(&events, &project_events) as u32;
+            buffered_bytes = buffered_events_bytes(&config, &events, &project_events);
+        }
+
+        // The error is sent as a string, it goes to all the flushes waiting
+        let flush_result = batch_result.map_err(|e| e.to_string());
+
+        // Notify the callers that the flush is done, the ones that timed out are gone. Emptied
+        // so the next batches (timeout, full batch) don't notify them again.
+        for notify_caller in notify_callers.drain(..) {
+            let _ = notify_caller.send(flush_result.clone());
+        }
+        for completer in async_flushes.drain(..) {
+            completer.complete(flush_result.clone().map_err(Error::SendFailed));
+        }
+
+        if stop_thread {
+            break;
+        }
+    }
+}
+
+// What the thread knows about the waiting events when it decides to send them
+struct BufferState {
+    flush_requested: bool,
+    stopping: bool,
+    events_qty: u32,
+    buffered_bytes: usize,
+    oldest_event_age: Option<Duration>,
+    interval_elapsed: bool,
+    collection_due: bool,
+    collection_full: bool,
+}
+
+// None if the events keep waiting
+fn flush_reason(config: &ClientConfig, buffer: &BufferState) -> Option<FlushReason> {
+    let reason = if buffer.stopping {
+        FlushReason::Stop
+    } else if buffer.flush_requested {
+        FlushReason::Flush
+    } else if buffer.events_qty >= config.max_events_per_request {
+        FlushReason::MaxCount
+    } else if config.flush_at_count.is_some_and(|flush_at_count| buffer.events_qty >= flush_at_count) {
+        FlushReason::Count
+    } else if config.flush_at_bytes.is_some_and(|flush_at_bytes| buffer.events_qty > 0 && buffer.buffered_bytes >= flush_at_bytes) {
+        FlushReason::Bytes
+    } else if config.flush_at_age.is_some_and(|flush_at_age| buffer.oldest_event_age.is_some_and(|age| age >= flush_at_age)) {
+        FlushReason::Age
+    } else if buffer.interval_elapsed {
+        FlushReason::Interval
+    } else if buffer.collection_full {
+        FlushReason::CollectionFull
+    } else if buffer.collection_due {
+        FlushReason::CollectionInterval
+    } else {
+        return None;
+    };
+    Some(reason)
+}
+
+// Only measured if flush_at_bytes is set
+fn buffered_event_bytes(config: &ClientConfig, json: &serde_json::Value) -> usize {
+    match config.flush_at_bytes {
+        Some(_) => serde_json::to_vec(json).map(|json| json.len()).unwrap_or(0),
+        None => 0,
+    }
+}
+
+// The events of the client project and of the other projects
+fn buffered_events_qty(
+    events: &HashMap<String, Vec<serde_json::Value>>,
+    project_events: &HashMap<ProjectKey, (ProjectSettings, HashMap<String, Vec<serde_json::Value>>)>,
+) -> usize {
+    events
+        .values()
+        .chain(project_events.values().flat_map(|(_, project_collections)| project_collections.values()))
+        .map(Vec::len)
+        .sum()
+}
+
+fn buffered_events_bytes(
+    config: &ClientConfig,
+    events: &HashMap<String, Vec<serde_json::Value>>,
+    project_events: &HashMap<ProjectKey, (ProjectSettings, HashMap<String, Vec<serde_json::Value>>)>,
+) -> usize {
+    if config.flush_at_bytes.is_none() {
+        return 0;
+    }
+
+    events
+        .values()
+        .chain(project_events.values().flat_map(|(_, project_collections)| project_collections.values()))
+        .flat_map(|collection_events| collection_events.iter())
+        .map(|json| buffered_event_bytes(config, json))
+        .sum()
+}
+
+fn transform_event(config: &ClientConfig, queue: &QueueState, collection: &str, json: serde_json::Value) -> Option<serde_json::Value> {
+    let transform = match config.transform {
+        Some(ref transform) => transform,
+        None => return Some(json),
+    };
+
+    let transformed = transform(collection, json);
+    if transformed.is_none() {
+        queue.remove(1);
+        queue.filtered_events.fetch_add(1, Ordering::SeqCst);
+    }
+    transformed
+}
+
+// 0 if a mock clock went back
+fn elapsed_since(clock: &dyn Clock, since: Instant) -> Duration {
+    clock.now().checked_duration_since(since).unwrap_or_default()
+}
+
+fn remaining_time(clock: &dyn Clock, since: Instant, interval: Duration) -> Duration {
+    let elapsed = elapsed_since(clock, since);
+    if interval > elapsed {
+        interval - elapsed
+    } else {
+        Duration::from_millis(0)
+    }
+}
+
+// Move out of events the collections that must not be sent now: the collections with their own
+// interval that is not elapsed, and the other ones if the global interval is not elapsed. The
+// full collections are always sent.
+fn hold_collections(
+    config: &ClientConfig,
+    events: &mut HashMap<String, Vec<serde_json::Value>>,
+    global_due: bool,
+    collection_sends: &HashMap<String, Instant>,
+    thread_start: Instant,
+) -> HashMap<String, Vec<serde_json::Value>> {
+    let (sent_events, held_events) = events.drain().partition(|(collection, collection_events)| {
+        if config.max_events_per_collection.is_some_and(|max_events| collection_events.len() >= max_events) {
+            return true;
+        }
+
+        match config.collection_send_intervals.get(collection) {
+            Some(&interval) => {
+                remaining_time(&*config.clock, *collection_sends.get(collection).unwrap_or(&thread_start), interval) == Duration::from_millis(0)
+            }
+            None => global_due,
+        }
+    });
+
+    *events = sent_events;
+    held_events
+}
+
+// Send events while other events are held. The held events are spooled after the sent ones so
+// they are not kept with the batch if it fails, and spooled again if it's sent.
+#[allow(clippy::too_many_arguments)]
+fn send_collections(
+    transport: &mut dyn Transport,
+    settings: &ProjectSettings,
+    config: &ClientConfig,
+    events: &mut HashMap<String, Vec<serde_json::Value>>,
+    held_events: &mut HashMap<String, Vec<serde_json::Value>>,
+    spool: &mut Option<EventSpool>,
+    queue: &QueueState,
+    collection_sends: &mut HashMap<String, Instant>,
+) -> Result<usize, Error> {
+    if events.is_empty() {
+        return Ok(0);
+    }
+
+    for collection in events.keys() {
+        if config.collection_send_intervals.contains_key(collection) {
+            collection_sends.insert(collection.clone(), config.clock.now());
+        }
+    }
+
+    if !held_events.is_empty() {
+        if let Some(ref mut spool) = *spool {
+            if let Err(e) = spool.hold_events(events, held_events) {
+                error!("Event spool can't be updated: {}", e);
+            }
+        }
+    }
+
+    let batch_result = send_batch(transport, settings, config, events, spool, queue);
+
+    // If the batch failed, the held events are still in the spool after it
+    if batch_result.is_ok() {
+        for (collection, jsons) in held_events.iter() {
+            for json in jsons {
+                spool_event(spool, collection, json);
+            }
+        }
+    }
+
+    batch_result
+}
+
+fn jittered_interval(interval: Duration, jitter: Option<f64>) -> Duration {
+    match jitter {
+        Some(jitter) if jitter > 0.0 => interval.mul_f64(1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0)),
+        _ => interval,
+    }
+}
+
+fn add_heartbeat(config: &ClientConfig, events: &mut HashMap<String, Vec<serde_json::Value>>, queue: &QueueState) {
+    if let Some((ref collection, ref json)) = config.heartbeat {
+        let collection_name = match config.collection_name(collection) {
+            Ok(collection_name) => collection_name,
+            Err(e) => {
+                error!("Heartbeat can't be sent: {}", e);
+                return;
+            }
+        };
+
+        let mut heartbeat = json.clone();
+        if let Some(object) = heartbeat.as_object_mut() {
+            let keen_info = KeenInfo::new(Utc::now().to_rfc3339_opts(config.timestamp_format, true));
+            if let Ok(keen_info) = serde_json::to_value(&keen_info) {
+                object.insert(config.metadata_key.clone(), keen_info);
+            }
+        }
+
+        // The heartbeat goes through the transform hook like the events
+        queue.add(1);
+        if let Some(heartbeat) = transform_event(config, queue, &collection_name, heartbeat) {
+            events.insert(collection_name, vec![heartbeat]);
+        }
+    }
+}
+
+fn spool_event(spool: &mut Option<EventSpool>, collection: &str, json: &serde_json::Value) {
+    if let Some(ref mut spool) = *spool {
+        if let Err(e) = spool.append(collection, json) {
+            error!("Event can't be spooled: {}", e);
+        }
+    }
+}
+
+fn send_batch(
+    transport: &mut dyn Transport,
+    settings: &ProjectSettings,
+    config: &ClientConfig,
+    events: &mut HashMap<String, Vec<serde_json::Value>>,
+    spool: &mut Option<EventSpool>,
+    queue: &QueueState,
+) -> Result<usize, Error> {
+    if events.is_empty() {
+        return Ok(0);
+    }
+
+    let events_qty: usize = events.values().map(Vec::len).sum();
+    if let Some(max_event_age) = config.max_event_age {
+        drop_expired_events(events, &config.metadata_key, max_event_age, queue);
+        if events.is_empty() {
+            if let Some(ref mut spool) = *spool {
+                if let Err(e) = spool.batch_sent() {
+                    error!("Event spool can't be updated: {}", e);
+                }
+            }
+            queue.remove(events_qty);
+            return Ok(0);
+        }
+    }
+
+    trace!(events = events_qty, collections = events.len(); "Sending events: {} events to send!", events_qty);
+    if let Some(ref count_property) = config.coalesce_property {
+        coalesce_events(events, config, count_property);
+    }
+
+    let mut events_accepted = 0;
+    let mut send_error = None;
+    let request_id = set_request_id(config, events);
+    match serde_json::to_vec(&*events) {
+        Ok(ref body) if body.len() <= config.max_request_bytes => {
+            match send_request(transport, settings, config, events, body, queue, request_id.as_deref()) {
+                Ok(accepted) => events_accepted = accepted,
+                Err(e) => send_error = Some(e),
+            }
+        }
+        Ok(body) => {
+            let chunks = split_batch(mem::take(events), config.max_request_bytes);
+            trace!("Batch of {} bytes split in {} requests", body.len(), chunks.len());
+            // The body of the whole batch is not needed anymore
+            drop(body);
+            for mut chunk in chunks {
+                // Each request has its own id
+                let request_id = set_request_id(config, &mut chunk);
+                let result = serde_json::to_vec(&chunk)
+                    .map_err(|e| serialization_failed(config, e.into(), &chunk, queue))
+                    .and_then(|chunk_body| {
+                        send_request(transport, settings, config, &chunk, &chunk_body, queue, request_id.as_deref())
+                    });
+                match result {
+                    Ok(accepted) => events_accepted += accepted,
+                    Err(e) => send_error = Some(e),
+                }
+            }
+        }
+        Err(e) => send_error = Some(serialization_failed(config, e.into(), events, queue)),
+    }
+
+    // If a part of the batch failed, the whole batch stays in the spool
+    if let Some(ref mut spool) = *spool {
+        let spool_result = if send_error.is_none() { spool.batch_sent() } else { spool.batch_failed() };
+        if let Err(e) = spool_result {
+            error!("Event spool can't be updated: {}", e);
+        }
+    }
+
+    events.clear();
+    queue.remove(events_qty);
+
+    match send_error {
+        Some(e) => Err(e),
+        None => Ok(events_accepted),
+    }
+}
+
+// Send the event from the calling thread, an error if Keen rejected it. The event goes through
+// the transform hook, dry run and ring buffer like a batch of the thread, Ok if the hook drops it.
+fn send_event_now(settings: &ProjectSettings, config: &ClientConfig, collection: String, event: serde_json::Value, queue: &QueueState) -> Result<(), Error> {
+    // Pending while it's sent, the transform hook removes the events it drops
+    queue.add(1);
+    queue.accepted_events.fetch_add(1, Ordering::SeqCst);
+    metrics_recorder::events_accepted(1);
+    let event = match transform_event(config, queue, &collection, event) {
+        Some(event) => event,
+        None => return Ok(()),
+    };
+
+    let mut events = HashMap::new();
+    events.insert(collection, vec![event]);
+    let request_id = set_request_id(config, &mut events);
+    let result = serde_json::to_vec(&events)
+        .map_err(|e| serialization_failed(config, e.into(), &events, queue))
+        .and_then(|body| send_request(&mut *config.new_transport(), settings, config, &events, &body, queue, request_id.as_deref()));
+    queue.remove(1);
+    if result? == 0 {
+        return Err(Error::SendFailed("event rejected by Keen".to_string()));
+    }
+    Ok(())
+}
+
+// Set a new request id in the events if request_id_property is set
+fn set_request_id(config: &ClientConfig, events: &mut HashMap<String, Vec<serde_json::Value>>) -> Option<String> {
+    let request_id_property = config.request_id_property.as_ref()?;
+    let request_id = Uuid::new_v4().to_string();
+    for event in events.values_mut().flat_map(|collection_events| collection_events.iter_mut()) {
+        if let Some(object) = event.as_object_mut() {
+            object.insert(request_id_property.clone(), json!(request_id));
+        }
+    }
+    Some(request_id)
+}
+
+// The events can't be sent, handled like a failed request
+fn serialization_failed(config: &ClientConfig, error: Error, events: &HashMap<String, Vec<serde_json::Value>>, queue: &QueueState) -> Error {
+    if let Some(level) = config.failure_log_level.to_level() {
+        log!(level, "Events can't be serialized: {}", error);
+    }
+    queue.send_failures.fetch_add(1, Ordering::SeqCst);
+    metrics_recorder::send_failed(events.values().map(Vec::len).sum(), Duration::from_millis(0));
+    *queue.last_error.lock().unwrap() = Some(error.to_string());
+    queue.publish_result(events, BatchOutcome::Failed(error.to_string()));
+    if let Some(ref on_error) = config.on_error {
+        on_error(&error, events.values().map(Vec::len).sum());
+    }
+    error
+}
+
+// Return the number of events accepted by Keen
+fn send_request(
+    transport: &mut dyn Transport,
+    settings: &ProjectSettings,
+    config: &ClientConfig,
+    events: &HashMap<String, Vec<serde_json::Value>>,
+    body: &[u8],
+    queue: &QueueState,
+    request_id: Option<&str>,
+) -> Result<usize, Error> {
+    // The batch logs have key/value fields for the structured loggers
+    let events_qty: usize = events.values().map(Vec::len).sum();
+    let collections_qty = events.len();
+
+    // The settings are shared with the other requests, the failover state too
+    let request_settings;
+    let settings = match request_id {
+        Some(request_id) => {
+            request_settings = settings.clone().with_header("X-Request-Id", request_id);
+            &request_settings
+        }
+        None => settings,
+    };
+    let request_id = request_id.unwrap_or("");
+
+    if config.dry_run {
+        trace!(events = events_qty, collections = collections_qty, bytes = body.len(), request_id = request_id, outcome = "dry_run";
+            "Dry run: batch of {} events not sent: {}", events_qty, String::from_utf8_lossy(body));
+        queue.batches.fetch_add(1, Ordering::SeqCst);
+        queue.sent_events.fetch_add(occurrences(config, events.values().flatten()), Ordering::SeqCst);
+        metrics_recorder::batch_sent(events_qty, events_qty, Duration::from_millis(0));
+        queue.publish_result(events, BatchOutcome::Sent { events_accepted: events_qty });
+        if let Some(ref on_success) = config.on_success {
+            on_success(&collection_counts(events));
+        }
+        return Ok(events_qty);
+    }
+
+    if let Some(capacity) = config.ring_buffer {
+        let mut recent_events = queue.recent_events.lock().unwrap();
+        for (collection, collection_events) in events {
+            for event in collection_events {
+                if recent_events.len() >= capacity {
+                    recent_events.pop_front();
+                }
+                if capacity > 0 {
+                    recent_events.push_back((collection.clone(), event.clone()));
+                }
+            }
+        }
+        trace!(events = events_qty, collections = collections_qty, outcome = "ring_buffer";
+            "Batch of {} events kept in the ring buffer", events_qty);
+        queue.batches.fetch_add(1, Ordering::SeqCst);
+        queue.sent_events.fetch_add(occurrences(config, events.values().flatten()), Ordering::SeqCst);
+        metrics_recorder::batch_sent(events_qty, events_qty, Duration::from_millis(0));
+        queue.publish_result(events, BatchOutcome::Sent { events_accepted: events_qty });
+        if let Some(ref on_success) = config.on_success {
+            on_success(&collection_counts(events));
+        }
+        return Ok(events_qty);
+    }
+
+    let start = Instant::now();
+    let result = post_to_keen_with_retry(transport, settings, body, &config.retry_policy);
+    let duration = start.elapsed();
+    let duration_ms = duration.as_millis() as u64;
+    queue.batches.fetch_add(1, Ordering::SeqCst);
+    // In the message too, for the loggers that don't print the fields
+    let request_text = if request_id.is_empty() { String::new() } else { format!(" (request {})", request_id) };
+    match result {
+        Ok(ref response) => {
+            let (accepted_by_collection, accepted_occurrences) = log_keen_response(events, response, config);
+            let events_accepted = accepted_by_collection.values().sum();
+            if let Some(level) = config.success_log_level.to_level() {
+                log!(level, events = events_qty, collections = collections_qty, bytes = body.len(), duration_ms = duration_ms,
+                    accepted = events_accepted, request_id = request_id, outcome = "sent";
+                    "Batch sent in {} ms: {}/{} events accepted{}", duration_ms, events_accepted, events_qty, request_text);
+            }
+            queue.sent_events.fetch_add(accepted_occurrences, Ordering::SeqCst);
+            metrics_recorder::batch_sent(events_qty, events_accepted, duration);
+            queue.last_error.lock().unwrap().take();
+            queue.publish_result(events, BatchOutcome::Sent { events_accepted });
+            if let Some(ref on_success) = config.on_success {
+                on_success(&accepted_by_collection);
+            }
+            Ok(events_accepted)
+        },
+        Err(Error::NotStarted) => {
+            trace!("Events can't be sent: {}", Error::NotStarted);
+            Err(Error::NotStarted)
+        },
+        Err(e) => {
+            if let Some(level) = config.failure_log_level.to_level() {
+                log!(level, events = events_qty, collections = collections_qty, bytes = body.len(), duration_ms = duration_ms,
+                    request_id = request_id, outcome = "failed";
+                    "Events can't be sent{}: {}", request_text, e);
+            }
+            queue.send_failures.fetch_add(1, Ordering::SeqCst);
+            metrics_recorder::send_failed(events_qty, duration);
+            *queue.last_error.lock().unwrap() = Some(e.to_string());
+            queue.publish_result(events, BatchOutcome::Failed(e.to_string()));
+            if let Some(ref on_error) = config.on_error {
+                on_error(&e, events_qty);
+            }
+            Err(e)
+        },
+    }
+}
+
+fn drop_expired_events(
+    events: &mut HashMap<String, Vec<serde_json::Value>>,
+    metadata_key: &str,
+    max_event_age: Duration,
+    queue: &QueueState,
+) {
+    let now = Utc::now();
+    let mut expired_qty = 0;
+    for collection_events in events.values_mut() {
+        let events_qty = collection_events.len();
+        // The events without a valid timestamp are kept
+        collection_events.retain(|event| {
+            event
+                .get(metadata_key)
+                .and_then(|keen| keen.get("timestamp"))
+                .and_then(|timestamp| timestamp.as_str())
+                .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+                .and_then(|timestamp| now.signed_duration_since(timestamp).to_std().ok())
+                .is_none_or(|age| age <= max_event_age)
+        });
+        expired_qty += events_qty - collection_events.len();
+    }
+    events.retain(|_, collection_events| !collection_events.is_empty());
+
+    if expired_qty > 0 {
+        queue.expired_events.fetch_add(expired_qty, Ordering::SeqCst);
+        warn!("{} events older than {:?} dropped", expired_qty, max_event_age);
+    }
+}
+
+fn coalesce_events(events: &mut HashMap<String, Vec<serde_json::Value>>, config: &ClientConfig, count_property: &str) {
+    for collection_events in events.values_mut() {
+        let mut coalesced = Vec::new();
+        let mut occurrences = Vec::new();
+        // Index in coalesced of each distinct event
+        let mut indexes = HashMap::new();
+
+        for event in collection_events.drain(..) {
+            let mut identity = event.clone();
+            if let Some(object) = identity.as_object_mut() {
+                object.remove(&config.metadata_key);
+                for property in config.event_id_property.iter().chain(config.sequence_property.iter()) {
+                    object.remove(property);
+                }
+            }
+
+            // The properties are sorted, identical events give the same json
+            let identity = serde_json::to_string(&identity).ok();
+            match identity.as_ref().and_then(|identity| indexes.get(identity)).cloned() {
+                Some(index) => occurrences[index] += 1,
+                None => {
+                    if let Some(identity) = identity {
+                        indexes.insert(identity, coalesced.len());
+                    }
+                    coalesced.push(event);
+                    occurrences.push(1u64);
+                }
+            }
+        }
+
+        for (event, count) in coalesced.iter_mut().zip(occurrences) {
+            if let Some(object) = event.as_object_mut() {
+                object.insert(count_property.to_owned(), json!(count));
+            }
+        }
+        *collection_events = coalesced;
+    }
+}
+
+// Split the events in batches of at most max_bytes once serialized. An event bigger than
+// max_bytes is sent alone.
+fn split_batch(events: HashMap<String, Vec<serde_json::Value>>, max_bytes: usize) -> Vec<HashMap<String, Vec<serde_json::Value>>> {
+    let mut chunks = Vec::new();
+    let mut chunk: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    let mut chunk_bytes = 2; // {}
+
+    for (collection, collection_events) in events {
+        for event in collection_events {
+            let event_bytes = serde_json::to_string(&event).map(|json| json.len()).unwrap_or(0) + 1;
+            // "collection":[],
+            let mut collection_bytes = if chunk.contains_key(&collection) { 0 } else { collection.len() + 6 };
+
+            if !chunk.is_empty() && chunk_bytes + collection_bytes + event_bytes > max_bytes {
+                chunks.push(chunk);
+                chunk = HashMap::new();
+                chunk_bytes = 2;
+                collection_bytes = collection.len() + 6;
+            }
+
+            chunk_bytes += collection_bytes + event_bytes;
+            chunk.entry(collection.clone()).or_default().push(event);
+        }
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+// Keen returns the result of each event, some of them can be rejected while the others are accepted.
+// Return the number of events accepted in each collection, and the total of their occurrences
+// (a coalesced event counts for all the events it replaces). The rejected events are logged at
+// failure_log_level.
+fn log_keen_response(events: &HashMap<String, Vec<serde_json::Value>>, response: &str, config: &ClientConfig) -> (HashMap<String, usize>, usize) {
+    let results: HashMap<String, Vec<KeenEventResult>> = match serde_json::from_str(response) {
+        Ok(results) => results,
+        Err(e) => {
+            warn!("Keen response can't be parsed: {}. Response: {}", e, response);
+            return (collection_counts(events), occurrences(config, events.values().flatten()));
+        }
+    };
+
+    let mut accepted_by_collection = HashMap::new();
+    let mut events_accepted = 0;
+    let mut events_rejected = 0;
+    let mut accepted_occurrences = 0;
+    for (collection, collection_results) in &results {
+        let collection_events = events.get(collection);
+        for (index, event_result) in collection_results.iter().enumerate() {
+            let event = collection_events.and_then(|collection_events| collection_events.get(index));
+            if event_result.success {
+                events_accepted += 1;
+                accepted_occurrences += occurrences(config, event);
+                *accepted_by_collection.entry(collection.clone()).or_insert(0) += 1;
+                continue;
+            }
+
+            events_rejected += 1;
+            let level = match config.failure_log_level.to_level() {
+                Some(level) => level,
+                None => continue,
+            };
+            match event_result.error {
+                Some(ref keen_error) => log!(
+                    level,
+                    "Event rejected by Keen in collection \"{}\": {} ({}). Event: {:?}",
+                    collection, keen_error.description, keen_error.name, event
+                ),
+                None => log!(level, "Event rejected by Keen in collection \"{}\". Event: {:?}", collection, event),
+            }
+        }
+    }
+
+    trace!("Events sent: {} accepted, {} rejected. Response: {}", events_accepted, events_rejected, response);
+    (accepted_by_collection, accepted_occurrences)
+}
+
+// Number of added events the events stand for, an event coalesced by coalesce_events stands
+// for its count
+fn occurrences<'a, I: IntoIterator<Item = &'a serde_json::Value>>(config: &ClientConfig, events: I) -> usize {
+    events
+        .into_iter()
+        .map(|event| {
+            config
+                .coalesce_property
+                .as_ref()
+                .and_then(|count_property| event.get(count_property))
+                .and_then(serde_json::Value::as_u64)
+                .map_or(1, |count| count as usize)
+        })
+        .sum()
+}
+
+fn collection_counts(events: &HashMap<String, Vec<serde_json::Value>>) -> HashMap<String, usize> {
+    events
+        .iter()
+        .map(|(collection, collection_events)| (collection.clone(), collection_events.len()))
+        .collect()
+}
+
+fn post_to_keen_with_retry(transport: &mut dyn Transport, settings: &ProjectSettings, body: &[u8], retry_policy: &RetryPolicy) -> Result<String, Error> {
+    let mut attempt = 1;
+    loop {
+        match post_to_keen(transport, settings, body) {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                // The request is refused, it would be refused again
+                let refused = match e {
+                    Error::HttpStatus { code, .. } => (400..500).contains(&code) && code != 408 && code != 429,
+                    _ => false,
+                };
+                if refused || attempt >= retry_policy.max_attempts {
+                    return Err(e);
+                }
+
+                let delay = retry_policy.delay(attempt);
+                warn!("Events can't be sent (attempt {}/{}): {}. Retrying in {:?}", attempt, retry_policy.max_attempts, e, delay);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Try the domains in order, starting from the last one that worked. Return the response body.
+fn post_to_keen(transport: &mut dyn Transport, settings: &ProjectSettings, body: &[u8]) -> Result<String, Error> {
+    let domain_urls = settings.domain_urls();
+    let active_domain = settings.active_domain.load(Ordering::SeqCst) % domain_urls.len();
+
+    let mut result = Err(Error::SendFailed("No domain to send the events to".to_string()));
+    for attempt in 0..domain_urls.len() {
+        let index = (active_domain + attempt) % domain_urls.len();
+        result = post_to_domain(transport, settings, domain_urls[index], body);
+        match result {
+            Ok(_) => {
+                if index != active_domain {
+                    warn!("Events are now sent to {} (failover from {})", domain_urls[index], domain_urls[active_domain]);
+                    settings.active_domain.store(index, Ordering::SeqCst);
+                }
+                break;
+            }
+            // The request is refused, another domain would refuse it too
+            Err(Error::HttpStatus { code, .. }) if (400..500).contains(&code) && code != 408 && code != 429 => break,
+            Err(ref e) => {
+                if domain_urls.len() > 1 {
+                    warn!("Events can't be sent to {}: {}", domain_urls[index], e);
+                }
+            }
+        }
+    }
+    result
+}
+
+fn post_to_domain(transport: &mut dyn Transport, settings: &ProjectSettings, domain_url: &str, body: &[u8]) -> Result<String, Error> {
+    let url = keen_url(settings, domain_url, "events", &settings.api_key, &[]);
+
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    if settings.authorization_header {
+        headers.push(("Authorization".to_string(), settings.api_key.clone()));
+    }
+    headers.push(("User-Agent".to_string(), settings.user_agent.clone()));
+    headers.extend(settings.headers.iter().cloned());
+
+    // The compressed body is a second buffer, the uncompressed one is kept for the failover and
+    // the retries
+    let response = if settings.compression {
+        headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        transport.post(settings, &url, &headers, &gzip(body)?)?
+    } else {
+        transport.post(settings, &url, &headers, body)?
+    };
+
+    response_body(response)
+}
+
+// The body of a successful response, or the status and the body of the error
+fn response_body(response: Response) -> Result<String, Error> {
+    if response.status >= 200 && response.status < 300 {
+        Ok(response.body)
+    } else {
+        Err(Error::HttpStatus {
+            code: response.status,
+            body: response.body,
+        })
+    }
+}
+
+fn get_from_keen(transport: &mut dyn Transport, settings: &ProjectSettings, path: &str, api_key: &str, params: &[(&str, &str)]) -> Result<String, Error> {
+    let domain_urls = settings.domain_urls();
+    let domain_url = domain_urls[settings.active_domain.load(Ordering::SeqCst) % domain_urls.len()];
+    let url = keen_url(settings, domain_url, path, api_key, params);
+
+    let mut headers = Vec::new();
+    if settings.authorization_header {
+        headers.push(("Authorization".to_string(), api_key.to_string()));
+    }
+    headers.push(("User-Agent".to_string(), settings.user_agent.clone()));
+    headers.extend(settings.headers.iter().cloned());
+
+    response_body(transport.get(settings, &url, &headers)?)
+}
+
+// Open the connection to the domain the events are sent to, the response doesn't matter
+fn prewarm_connection(transport: &mut dyn Transport, settings: &ProjectSettings) {
+    let domain_urls = settings.domain_urls();
+    let domain_url = domain_urls[settings.active_domain.load(Ordering::SeqCst) % domain_urls.len()];
+    let path_prefix = settings.path_prefix.as_ref().map_or("", |path_prefix| path_prefix.as_str());
+    let url = format!("{}{}/3.0", domain_url, path_prefix);
+
+    let start = Instant::now();
+    let headers = vec![("User-Agent".to_string(), settings.user_agent.clone())];
+    match transport.get(settings, &url, &headers) {
+        Ok(_) => trace!("Connected to {} in {} ms", domain_url, start.elapsed().as_millis()),
+        Err(e) => warn!("Connection to {} can't be opened: {}", domain_url, e),
+    }
+}
+
+// Url of the project endpoint. The api key is in the query string if it's not sent in the Authorization header.
+fn keen_url(settings: &ProjectSettings, domain_url: &str, path: &str, api_key: &str, params: &[(&str, &str)]) -> String {
+    let path_prefix = settings.path_prefix.as_ref().map_or("", |path_prefix| path_prefix.as_str());
+    let mut url = format!("{}{}/3.0/projects/{}/{}", domain_url, path_prefix, settings.project_id, path);
+
+    let mut params = params.to_vec();
+    if !settings.authorization_header {
+        params.push(("api_key", api_key));
+    }
+
+    for (index, &(name, value)) in params.iter().enumerate() {
+        url.push(if index == 0 { '?' } else { '&' });
+        url.push_str(name);
+        url.push('=');
+        url.push_str(&url_encode(value));
+    }
+
+    url
+}
+
+// Percent-encode everything but the unreserved characters
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn gzip(body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    Ok(encoder.finish()?)
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+enum Event {
+    KeenEvent(String, serde_json::Value),
+    KeenEvents(String, Vec<serde_json::Value>),
+    ProjectEvent(Box<ProjectSettings>, String, serde_json::Value),
+    Flush(Option<Sender<FlushResult>>), // Channel of the result if the caller waits for the flush
+    AsyncFlush(FlushCompleter),
+    Discard,
+    UpdateSettings(Box<ProjectSettings>),
+    Resume,  // Sent by set_enabled(true) to wake up the thread
+    Tick,    // Sent by tick() to check the intervals against the clock
+    Waiting, // Sent when the first event is added to waiting_events
+}
+
+#[derive(Debug, Default)]
+struct FlushState {
+    result: Option<Result<usize, Error>>,
+    completed: bool,
+    waker: Option<Waker>,
+}
+
+// Resolve to the number of events sent by the flush
+#[derive(Debug)]
+pub struct FlushFuture {
+    state: Arc<Mutex<FlushState>>,
+}
+
+impl FlushFuture {
+    fn new() -> (FlushFuture, FlushCompleter) {
+        let state = Arc::new(Mutex::new(FlushState::default()));
+        (FlushFuture { state: state.clone() }, FlushCompleter { state })
+    }
+}
+
+impl Future for FlushFuture {
+    type Output = Result<usize, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None if state.completed => Poll::Ready(Err(Error::NotStarted)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Sent to the thread with the flush event. If it's dropped without being completed (the
+// thread is stopped), the future fails instead of waiting forever.
+#[derive(Debug)]
+struct FlushCompleter {
+    state: Arc<Mutex<FlushState>>,
+}
+
+impl FlushCompleter {
+    fn complete(&self, result: Result<usize, Error>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.completed {
+            state.completed = true;
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for FlushCompleter {
+    fn drop(&mut self) {
+        self.complete(Err(Error::NotStarted));
+    }
+}
+
+#[derive(Deserialize)]
+struct KeenEventResult {
+    success: bool,
+    #[serde(default)]
+    error: Option<KeenEventError>,
+}
+
+#[derive(Deserialize)]
+struct KeenEventError {
+    name: String,
+    description: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeenInfo {
+    timestamp: String,
+    // Not serialized for the events without addon
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    addons: Vec<KeenAddons>,
+}
+
+impl KeenInfo {
+    fn new(timestamp: String) -> Self {
+        KeenInfo {
+            timestamp,
+            addons: Vec::new(),
+        }
+    }
+
+    fn add_addon(&mut self, addon: KeenAddons) {
+        self.addons.push(addon);
+    }
+}
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeenAddons {
+    name: String,
+    input: KeenInput,
+    output: String,
+}
+
+impl KeenAddons {
+    pub fn build_ip_geo_addons(
+        input_field_name: &str,
+        output_field_name: &str,
+        remove_ip_property: bool,
+    ) -> Self {
+        KeenAddons {
+            name: "keen:ip_to_geo".to_string(),
+            input: KeenInput::IpToGeo {
+                ip: input_field_name.to_string(),
+                remove_ip_property,
+                ip_placeholder: true,
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+
+    // The input property is an ip set by the caller instead of the ip of the request
+    pub fn build_ip_geo_addons_from_property(
+        input_field_name: &str,
+        output_field_name: &str,
+        remove_ip_property: bool,
+    ) -> Self {
+        KeenAddons {
+            name: "keen:ip_to_geo".to_string(),
+            input: KeenInput::IpToGeo {
+                ip: input_field_name.to_string(),
+                remove_ip_property,
+                ip_placeholder: false,
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+
+    pub fn build_datetime_parser(input_field_name: &str, output_field_name: &str) -> Self {
+        KeenAddons {
+            name: "keen:date_time_parser".to_string(),
+            input: KeenInput::DateTime {
+                date_time: input_field_name.to_string(),
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+
+    pub fn build_ua_parser(input_field_name: &str, output_field_name: &str) -> Self {
+        KeenAddons {
+            name: "keen:ua_parser".to_string(),
+            input: KeenInput::UserAgent {
+                ua_string: input_field_name.to_string(),
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+
+    pub fn build_url_parser(input_field_name: &str, output_field_name: &str) -> Self {
+        KeenAddons {
+            name: "keen:url_parser".to_string(),
+            input: KeenInput::Url {
+                url: input_field_name.to_string(),
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+
+    pub fn build_referrer_parser(
+        referrer_url_field_name: &str,
+        page_url_field_name: &str,
+        output_field_name: &str,
+    ) -> Self {
+        KeenAddons {
+            name: "keen:referrer_parser".to_string(),
+            input: KeenInput::Referrer {
+                referrer_url: referrer_url_field_name.to_string(),
+                page_url: page_url_field_name.to_string(),
+            },
+            output: output_field_name.to_string(),
+        }
+    }
+}
+
+// Each addon has its own input fields
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum KeenInput {
+    IpToGeo {
+        ip: String,
+        remove_ip_property: bool,
+        // Set the input property to ${keen.ip}, the ip of the request
+        #[serde(skip)]
+        ip_placeholder: bool,
+    },
+    DateTime {
+        date_time: String,
+    },
+    UserAgent {
+        ua_string: String,
+    },
+    Url {
+        url: String,
+    },
+    Referrer {
+        referrer_url: String,
+        page_url: String,
+    },
+}
+
+// Define an enum of the collections of the application, usable with add_event_into:
+//
+// keen_collections! {
+//     pub enum Collection {
+//         SessionStart => "session_start",
+//         SessionEnd => "session_end",
+//     }
+// }
+#[macro_export]
+macro_rules! keen_collections {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident { $($variant:ident => $collection:expr),* $(,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($variant),*
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                match *self {
+                    $($name::$variant => $collection),*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Request received by the mock transport
+    struct MockRequest {
+        url: String,
+        events: HashMap<String, Vec<serde_json::Value>>,
+    }
+
+    // Keen api accepting all the events, or refusing the requests with failure_status (only the
+    // requests to failing_domain if it's set). The clones share the requests, the transport of
+    // each thread is a clone.
+    #[derive(Clone, Default)]
+    struct MockTransport {
+        requests: Arc<Mutex<Vec<MockRequest>>>,
+        failure_status: Arc<Mutex<Option<u32>>>,
+        failing_domain: Arc<Mutex<Option<String>>>,
+        response_delay: Arc<Mutex<Duration>>,
+    }
+
+    impl MockTransport {
+        fn fail_with(&self, failure_status: Option<u32>) {
+            *self.failure_status.lock().unwrap() = failure_status;
+        }
+
+        // A domain that is down, the requests to the other domains succeed
+        fn fail_domain(&self, domain_url: &str, failure_status: u32) {
+            *self.failing_domain.lock().unwrap() = Some(domain_url.to_string());
+            self.fail_with(Some(failure_status));
+        }
+
+        // A slow network
+        fn respond_after(&self, response_delay: Duration) {
+            *self.response_delay.lock().unwrap() = response_delay;
+        }
+
+        // The events of the collection in all the requests, in the order they were sent
+        fn events(&self, collection: &str) -> Vec<serde_json::Value> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|request| request.events.get(collection).cloned().unwrap_or_default())
+                .collect()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn post(&mut self, _settings: &ProjectSettings, url: &str, _headers: &[(String, String)], body: &[u8]) -> Result<Response, Error> {
+            let response_delay = *self.response_delay.lock().unwrap();
+            thread::sleep(response_delay);
+            let events: HashMap<String, Vec<serde_json::Value>> = serde_json::from_slice(body)?;
+            let results: HashMap<&String, Vec<serde_json::Value>> = events
+                .iter()
+                .map(|(collection, collection_events)| (collection, collection_events.iter().map(|_| json!({ "success": true })).collect()))
+                .collect();
+            let failing = match *self.failing_domain.lock().unwrap() {
+                Some(ref domain_url) => url.starts_with(domain_url.as_str()),
+                None => true,
+            };
+            let response = match *self.failure_status.lock().unwrap() {
+                Some(status) if failing => Response { status, body: "mock failure".to_string() },
+                _ => Response { status: 200, body: serde_json::to_string(&results)? },
+            };
+
+            self.requests.lock().unwrap().push(MockRequest { url: url.to_string(), events });
+            Ok(response)
+        }
+
+        fn get(&mut self, _settings: &ProjectSettings, _url: &str, _headers: &[(String, String)]) -> Result<Response, Error> {
+            Ok(Response { status: 200, body: "{}".to_string() })
+        }
+    }
+
+    // Wait for the thread to send the events
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "condition not met in 5 seconds");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn builder(transport: &MockTransport) -> KeenClientBuilder {
+        let transport = transport.clone();
+        KeenClient::builder(ProjectSettings::new(None, "project", "write_key")).transport(move || Box::new(transport.clone()))
+    }
+
+    #[test]
+    fn flush_waits_for_its_events_between_interval_sends() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_millis(5)).build();
+        keen.start();
+
+        for round in 0..20 {
+            keen.add_event("rounds", &json!({ "round": round })).unwrap();
+            // Some events are sent by the interval before the flush, some by the flush
+            thread::sleep(Duration::from_millis(round % 3 * 4));
+            keen.flush(true).unwrap();
+            assert_eq!(transport.events("rounds").len() as u64, round + 1);
+        }
+
+        keen.stop();
+    }
+
+    #[test]
+    fn existing_keen_property_is_not_overwritten() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).build();
+        keen.start();
+        match keen.add_event("reserved", &json!({ "keen": { "user": "data" } })) {
+            Err(Error::ReservedProperty(name)) => assert_eq!(name, "keen"),
+            _ => panic!("the keen property has to be refused"),
+        }
+        keen.flush(true).unwrap();
+        assert!(transport.events("reserved").is_empty());
+        keen.stop();
+
+        let transport = MockTransport::default();
+        let keen = builder(&transport).sanitize_properties(true).build();
+        keen.start();
+        keen.add_event("reserved", &json!({ "keen": { "user": "data" } })).unwrap();
+        keen.flush(true).unwrap();
+        let events = transport.events("reserved");
+        assert_eq!(events[0]["_keen"], json!({ "user": "data" }));
+        assert!(events[0]["keen"]["timestamp"].is_string());
+        keen.stop();
+    }
+
+    #[test]
+    fn flush_returns_the_send_error() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+
+        transport.fail_with(Some(500));
+        keen.add_event("failures", &json!({ "attempt": 1 })).unwrap();
+        match keen.flush(true) {
+            Err(Error::SendFailed(_)) => {}
+            _ => panic!("the flush has to return the error of the request"),
+        }
+        assert_eq!(keen.stats().send_failures, 1);
+
+        transport.fail_with(None);
+        keen.add_event("failures", &json!({ "attempt": 2 })).unwrap();
+        assert_eq!(keen.flush(true).unwrap(), 1);
+        keen.stop();
+    }
+
+    #[test]
+    fn client_can_be_restarted() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+        keen.stop();
+        assert!(!keen.is_running());
+
+        keen.start();
+        assert!(keen.is_running());
+        keen.add_event("restarts", &json!({ "started": 2 })).unwrap();
+        assert_eq!(keen.flush(true).unwrap(), 1);
+        assert_eq!(transport.events("restarts").len(), 1);
+        keen.stop();
+    }
+
+    #[test]
+    fn events_are_sent_to_a_http_domain() {
+        let transport = MockTransport::default();
+        let settings = ProjectSettings::new(Some("http://localhost:8080/".to_string()), "project", "write_key");
+        let mock = transport.clone();
+        let keen = KeenClient::builder(settings).transport(move || Box::new(mock.clone())).build();
+
+        keen.send_event_blocking("local", &json!({ "mock": true })).unwrap();
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests[0].url, "http://localhost:8080/3.0/projects/project/events?api_key=write_key");
+        assert_eq!(requests[0].events["local"].len(), 1);
+    }
+
+    #[test]
+    fn full_collection_is_sent_without_the_other_ones() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .max_events_per_collection(5)
+            .build();
+        keen.start();
+
+        for collection in &["low_a", "low_b", "low_c"] {
+            keen.add_event(collection, &json!({ "volume": "low" })).unwrap();
+        }
+        for index in 0..12 {
+            keen.add_event("high", &json!({ "index": index })).unwrap();
+        }
+
+        wait_until(|| transport.events("high").len() == 10);
+        for request in transport.requests.lock().unwrap().iter() {
+            assert_eq!(request.events.keys().collect::<Vec<_>>(), vec!["high"]);
+            assert_eq!(request.events["high"].len(), 5);
+        }
+
+        assert_eq!(keen.flush(true).unwrap(), 5);
+        assert_eq!(transport.events("high").len(), 12);
+        for collection in &["low_a", "low_b", "low_c"] {
+            assert_eq!(transport.events(collection).len(), 1);
+        }
+        keen.stop();
+    }
+
+    #[test]
+    fn addons_are_only_serialized_when_used() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+        keen.add_event("plain", &json!({ "page_url": "https://devolutions.net" })).unwrap();
+        keen.add_event_with_url_parsing("parsed", &json!({ "page_url": "https://devolutions.net" })).unwrap();
+        keen.flush(true).unwrap();
+
+        let plain_event = &transport.events("plain")[0];
+        assert!(plain_event["keen"].get("addons").is_none());
+        let parsed_event = &transport.events("parsed")[0];
+        assert_eq!(parsed_event["keen"]["addons"][0]["name"], "keen:url_parser");
+        assert_eq!(parsed_event["keen"]["addons"][0]["input"], json!({ "url": "page_url" }));
+        keen.stop();
+    }
+
+    #[test]
+    fn flush_waits_for_all_the_batches() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .max_events_per_request(10)
+            .build();
+        keen.start();
+        for index in 0..25 {
+            keen.add_event("backlog", &json!({ "index": index })).unwrap();
+        }
+        keen.flush(true).unwrap();
+
+        let events = transport.events("backlog");
+        assert_eq!(events.len(), 25);
+        assert!(events.iter().enumerate().all(|(index, event)| event["index"] == index));
+        assert!(transport.requests.lock().unwrap().iter().all(|request| request.events["backlog"].len() <= 10));
+        assert_eq!(keen.stats().pending_events, 0);
+        keen.stop();
+    }
+
+    #[test]
+    fn json_array_is_refused() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).build();
+        keen.start();
+        match keen.add_event("arrays", &json!([{ "event": 1 }, { "event": 2 }])) {
+            Err(Error::InvalidEventShape) => {}
+            _ => panic!("an array has to be refused"),
+        }
+        match keen.add_event_raw("arrays", "[1, 2]") {
+            Err(Error::InvalidEventShape) => {}
+            _ => panic!("an array has to be refused"),
+        }
+        assert_eq!(keen.stats().events_accepted, 0);
+        keen.stop();
+    }
+
+    // Clock advanced by the test
+    #[derive(Clone)]
+    struct MockClock {
+        now: Arc<Mutex<Instant>>,
+    }
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn events_are_sent_when_the_interval_is_elapsed() {
+        let transport = MockTransport::default();
+        let clock = MockClock { now: Arc::new(Mutex::new(Instant::now())) };
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(60))
+            .clock(clock.clone())
+            .build();
+        keen.start();
+        // The interval starts when the thread has started (and at each send)
+        keen.flush(true).unwrap();
+        keen.add_event("intervals", &json!({ "batch": 1 })).unwrap();
+
+        clock.advance(Duration::from_secs(59));
+        keen.tick();
+        thread::sleep(Duration::from_millis(50));
+        assert!(transport.events("intervals").is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        keen.tick();
+        wait_until(|| transport.events("intervals").len() == 1);
+        assert_eq!(keen.stats().last_flush_reason, Some(FlushReason::Interval));
+
+        // The next interval starts at the send
+        keen.add_event("intervals", &json!({ "batch": 2 })).unwrap();
+        clock.advance(Duration::from_secs(30));
+        keen.tick();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(transport.events("intervals").len(), 1);
+
+        clock.advance(Duration::from_secs(30));
+        keen.tick();
+        wait_until(|| transport.events("intervals").len() == 2);
+        keen.stop();
+    }
+
+    #[test]
+    fn stop_timeout_does_not_wait_for_a_waiting_flush() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+        transport.respond_after(Duration::from_millis(500));
+        keen.add_event("slow", &json!({ "network": "slow" })).unwrap();
+
+        let flushing_keen = keen.clone();
+        let flush = thread::spawn(move || flushing_keen.flush(true));
+        // The flush waits for the request
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(!keen.stop_timeout(Duration::from_millis(10)));
+        assert!(start.elapsed() < Duration::from_millis(250));
+        assert_eq!(flush.join().unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_queue_under_its_limit() {
+        let transport = MockTransport::default();
+        transport.respond_after(Duration::from_millis(200));
+        let keen = builder(&transport).max_queue(3, BackpressurePolicy::DropOldest).build();
+        keen.start();
+        keen.add_event("outage", &json!({ "index": 0 })).unwrap();
+        wait_until(|| keen.queue.waiting_events.lock().unwrap().is_empty());
+
+        // The thread is sending the first event, the oldest of the next ones are dropped
+        for index in 1..6 {
+            keen.add_event("outage", &json!({ "index": index })).unwrap();
+            assert!(keen.stats().pending_events <= 3);
+        }
+        keen.flush(true).unwrap();
+
+        let indexes: Vec<_> = transport.events("outage").iter().map(|event| event["index"].clone()).collect();
+        assert_eq!(indexes, vec![json!(0), json!(4), json!(5)]);
+        assert_eq!(keen.stats().events_dropped, 3);
+        keen.stop();
+    }
+
+    #[test]
+    fn held_events_are_not_kept_with_a_failed_batch() {
+        let spool_dir = std::env::temp_dir().join(format!("analytics-rs-held-events-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&spool_dir);
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .max_events_per_collection(2)
+            .spool_dir(&spool_dir)
+            .build();
+        keen.start();
+
+        // The full collection fails while the other one is held
+        transport.fail_with(Some(500));
+        keen.add_event("held", &json!({ "held": true })).unwrap();
+        keen.add_event("full", &json!({ "index": 0 })).unwrap();
+        keen.add_event("full", &json!({ "index": 1 })).unwrap();
+        wait_until(|| keen.stats().send_failures == 1);
+
+        transport.fail_with(None);
+        assert_eq!(keen.flush(true).unwrap(), 1);
+        keen.stop();
+
+        // Only the failed batch is replayed
+        keen.start();
+        keen.flush(true).unwrap();
+        keen.stop();
+        // The mock records the failed request too
+        assert_eq!(transport.events("held").len(), 1);
+        assert_eq!(transport.events("full").len(), 4);
+        let _ = std::fs::remove_dir_all(&spool_dir);
+    }
+
+    #[test]
+    fn coalesced_events_are_counted_by_occurrence() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .sequence_property("sequence")
+            .coalesce_events("count")
+            .build();
+        keen.start();
+        for _ in 0..3 {
+            keen.add_event("clicks", &json!({ "button": "ok" })).unwrap();
+        }
+        keen.flush(true).unwrap();
+
+        let events = transport.events("clicks");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["count"], 3);
+        let stats = keen.stats();
+        assert_eq!(stats.events_sent, 3);
+        assert_eq!(stats.events_sent, stats.events_accepted);
+        keen.stop();
+    }
+
+    #[test]
+    fn datetime_parsing_uses_the_metadata_key() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).metadata_key("meta").build();
+        keen.start();
+        keen.add_event_with_datetime_parsing("visits", &json!({ "page": "home" })).unwrap();
+        keen.flush(true).unwrap();
+
+        let event = &transport.events("visits")[0];
+        assert_eq!(event["meta"]["addons"][0]["input"], json!({ "date_time": "meta.timestamp" }));
+        keen.stop();
+    }
+
+    #[test]
+    fn sanitized_properties_do_not_overwrite_the_existing_ones() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).sanitize_properties(true).build();
+        keen.start();
+        keen.add_event("renamed", &json!({ "keen": 1, "_keen": 2, "a.b": 3, "a_b": 4 })).unwrap();
+        keen.flush(true).unwrap();
+
+        let event = &transport.events("renamed")[0];
+        assert_eq!(event["_keen"], 2);
+        assert_eq!(event["__keen"], 1);
+        assert_eq!(event["a_b"], 4);
+        assert_eq!(event["_a_b"], 3);
+        keen.stop();
+    }
+
+    #[test]
+    fn huge_timeouts_wait_without_timeout() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+        keen.add_event("timeouts", &json!({ "timeout": "max" })).unwrap();
+        assert!(keen.flush_timeout(Duration::MAX).unwrap());
+        assert!(keen.stop_timeout(Duration::MAX));
+        assert_eq!(transport.events("timeouts").len(), 1);
+    }
+
+    #[test]
+    fn concurrent_flushes_all_get_their_result() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+
+        let threads: Vec<_> = (0..4)
+            .map(|thread_index| {
+                let keen = keen.clone();
+                thread::spawn(move || {
+                    for index in 0..20 {
+                        keen.add_event("concurrent", &json!({ "thread": thread_index, "index": index })).unwrap();
+                        assert!(keen.flush_timeout(Duration::from_secs(5)).unwrap());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(transport.events("concurrent").len(), 80);
+        keen.stop();
+    }
+
+    #[test]
+    fn waiting_flushes_get_their_result_when_the_client_stops() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).build();
+        keen.start();
+        transport.respond_after(Duration::from_millis(300));
+        keen.add_event("slow", &json!({ "network": "slow" })).unwrap();
+
+        let flushes: Vec<_> = (0..3)
+            .map(|_| {
+                let flushing_keen = keen.clone();
+                thread::spawn(move || flushing_keen.flush(true))
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+
+        // The thread ends on its own after the slow request, the flushes are not blocked
+        assert!(!keen.stop_timeout(Duration::from_millis(10)));
+        for flush in flushes {
+            assert!(flush.join().unwrap().is_ok());
+        }
+        assert_eq!(transport.events("slow").len(), 1);
+    }
+
+    #[test]
+    fn sampling_applies_to_add_events_and_add_event_to() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).sample_rate(0.0).build();
+        keen.start();
+        let events: Vec<_> = (0..10).map(|index| json!({ "index": index })).collect();
+        keen.add_events("sampled", &events).unwrap();
+        keen.add_event_to(&ProjectSettings::new(None, "other_project", "write_key"), "sampled", &json!({ "index": 10 }))
+            .unwrap();
+        keen.flush(true).unwrap();
+
+        assert!(transport.requests.lock().unwrap().is_empty());
+        assert_eq!(keen.stats().events_sampled, 11);
+        assert_eq!(keen.stats().pending_events, 0);
+        keen.stop();
+    }
+
+    #[test]
+    fn block_tells_the_thread_to_send_the_full_queue() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .max_queue(3, BackpressurePolicy::Block)
+            .build();
+        keen.start();
+        keen.add_event("blocked", &json!({ "index": 0 })).unwrap();
+
+        // The batch doesn't fit next to the buffered event, the interval is never reached
+        let events = vec![json!({ "index": 1 }), json!({ "index": 2 }), json!({ "index": 3 })];
+        keen.add_events("blocked", &events).unwrap();
+        assert_eq!(transport.events("blocked").len(), 1);
+        assert_eq!(keen.stats().pending_events, 3);
+        keen.stop();
+        assert_eq!(transport.events("blocked").len(), 4);
+    }
+
+    #[test]
+    fn partial_sends_count_the_events_of_the_other_projects() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport)
+            .send_interval(Duration::from_secs(3600))
+            .max_events_per_collection(2)
+            .flush_at_count(4)
+            .build();
+        keen.start();
+        keen.add_event_to(&ProjectSettings::new(None, "other_project", "write_key"), "other", &json!({ "index": 0 }))
+            .unwrap();
+
+        // Only the full collection is sent, the event of the other project is still counted
+        keen.add_event("full", &json!({ "index": 1 })).unwrap();
+        keen.add_event("full", &json!({ "index": 2 })).unwrap();
+        wait_until(|| transport.events("full").len() == 2);
+        for collection in &["first", "second", "third"] {
+            keen.add_event(collection, &json!({ "index": 3 })).unwrap();
+        }
+        wait_until(|| transport.events("other").len() == 1);
+        assert_eq!(keen.stats().last_flush_reason, Some(FlushReason::Count));
+        keen.stop();
+    }
+
+    #[test]
+    fn flattened_properties_are_checked_and_kept_unique() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).flatten_properties(".").build();
+        keen.start();
+        match keen.add_event("flattened", &json!({ "keen": { "x": 1 } })) {
+            Err(Error::ReservedProperty(name)) => assert_eq!(name, "keen.x"),
+            _ => panic!("the flattened name with a period has to be refused"),
+        }
+        keen.stop();
+
+        let transport = MockTransport::default();
+        let keen = builder(&transport).flatten_properties("_").build();
+        keen.start();
+        keen.add_event("flattened", &json!({ "os_name": "plain", "os": { "name": "nested" }, "keen": { "x": 1 } })).unwrap();
+        keen.flush(true).unwrap();
+        let events = transport.events("flattened");
+        assert_eq!(events[0]["os_name"], json!("plain"));
+        assert_eq!(events[0]["_os_name"], json!("nested"));
+        assert_eq!(events[0]["keen_x"], json!(1));
+        assert!(events[0]["keen"]["timestamp"].is_string());
+        keen.stop();
+    }
+
+    #[test]
+    fn flush_reasons_follow_their_priority() {
+        let config = KeenClient::builder(ProjectSettings::new(None, "project", "write_key"))
+            .max_events_per_request(50)
+            .flush_at_count(10)
+            .flush_at_bytes(100)
+            .flush_at_age(Duration::from_secs(60))
+            .config;
+        let empty_buffer = || BufferState {
+            flush_requested: false,
+            stopping: false,
+            events_qty: 0,
+            buffered_bytes: 0,
+            oldest_event_age: None,
+            interval_elapsed: false,
+            collection_due: false,
+            collection_full: false,
+        };
+        assert_eq!(flush_reason(&config, &empty_buffer()), None);
+
+        // The state triggering each reason, in the order of FLUSH_REASONS. The events_qty of a
+        // reason is bigger than the one of the reasons after it.
+        let triggers: [fn(&mut BufferState); 9] = [
+            |buffer| buffer.stopping = true,
+            |buffer| buffer.flush_requested = true,
+            |buffer| buffer.events_qty = 50,
+            |buffer| buffer.events_qty = 10,
+            |buffer| {
+                buffer.events_qty = 1;
+                buffer.buffered_bytes = 100;
+            },
+            |buffer| buffer.oldest_event_age = Some(Duration::from_secs(60)),
+            |buffer| buffer.interval_elapsed = true,
+            |buffer| buffer.collection_full = true,
+            |buffer| buffer.collection_due = true,
+        ];
+        for (index, &reason) in FLUSH_REASONS.iter().enumerate() {
+            let mut buffer = empty_buffer();
+            triggers[index](&mut buffer);
+            assert_eq!(flush_reason(&config, &buffer), Some(reason), "{:?} alone", reason);
+
+            // All the reasons after it are true too
+            let mut buffer = empty_buffer();
+            for trigger in triggers[index..].iter().rev() {
+                trigger(&mut buffer);
+            }
+            assert_eq!(flush_reason(&config, &buffer), Some(reason), "{:?} with the next reasons", reason);
+        }
+
+        // The bytes don't count without events, and the thresholds that are not set are ignored
+        let mut buffer = empty_buffer();
+        buffer.buffered_bytes = 100;
+        assert_eq!(flush_reason(&config, &buffer), None);
+        let default_config = KeenClient::builder(ProjectSettings::new(None, "project", "write_key")).config;
+        let mut buffer = empty_buffer();
+        buffer.events_qty = 10;
+        buffer.buffered_bytes = 100;
+        buffer.oldest_event_age = Some(Duration::from_secs(3600));
+        assert_eq!(flush_reason(&default_config, &buffer), None);
+    }
+
+    #[test]
+    fn failover_domain_is_kept_for_the_next_batches() {
+        let transport = MockTransport::default();
+        let settings = ProjectSettings::new(Some("https://primary.example.com/".to_string()), "project", "write_key")
+            .with_failover_domain_url("https://failover.example.com");
+        let mock = transport.clone();
+        let keen = KeenClient::builder(settings).transport(move || Box::new(mock.clone())).build();
+        keen.start();
+        let urls = || transport.requests.lock().unwrap().iter().map(|request| request.url.clone()).collect::<Vec<_>>();
+
+        // The batch is sent again to the failover domain, which is kept for the next batches
+        transport.fail_domain("https://primary.example.com", 503);
+        keen.add_event("failover", &json!({ "index": 0 })).unwrap();
+        keen.flush(true).unwrap();
+        transport.fail_with(None);
+        keen.add_event("failover", &json!({ "index": 1 })).unwrap();
+        keen.flush(true).unwrap();
+        let urls = urls();
+        assert_eq!(urls.len(), 3);
+        assert!(urls[0].starts_with("https://primary.example.com/"));
+        assert!(urls[1].starts_with("https://failover.example.com/"));
+        assert!(urls[2].starts_with("https://failover.example.com/"));
+        assert_eq!(keen.stats().events_sent, 2);
+        keen.stop();
+    }
+
+    #[test]
+    fn failed_requests_are_retried_unless_refused() {
+        for &(status, attempts) in &[(503, 3), (408, 3), (429, 3), (400, 1), (413, 1)] {
+            let transport = MockTransport::default();
+            let keen = builder(&transport).send_interval(Duration::from_secs(3600)).retry(3, Duration::from_millis(0)).build();
+            keen.start();
+            transport.fail_with(Some(status));
+            keen.add_event("retried", &json!({ "status": status })).unwrap();
+            assert!(keen.flush(true).is_err());
+            assert_eq!(transport.requests.lock().unwrap().len(), attempts, "attempts with the status {}", status);
+            assert_eq!(keen.stats().send_failures, 1);
+            keen.stop();
+        }
+    }
+
+    #[test]
+    fn split_batch_keeps_the_chunks_under_the_limit() {
+        let mut events = HashMap::new();
+        events.insert("first".to_string(), (0..10).map(|index| json!({ "index": index })).collect::<Vec<_>>());
+        events.insert("second".to_string(), vec![json!({ "big": "x".repeat(100) }), json!({ "index": 10 })]);
+
+        let chunks = split_batch(events, 50);
+        let chunk_events: usize = chunks.iter().map(|chunk| chunk.values().map(Vec::len).sum::<usize>()).sum();
+        assert_eq!(chunk_events, 12);
+        for chunk in &chunks {
+            let chunk_bytes = serde_json::to_string(chunk).unwrap().len();
+            let big_event = chunk.get("second").is_some_and(|events| events.iter().any(|event| event.get("big").is_some()));
+            if big_event {
+                // The event bigger than the limit is sent alone
+                assert_eq!(chunk.values().map(Vec::len).sum::<usize>(), 1);
+            } else {
+                assert!(chunk_bytes <= 50, "chunk of {} bytes", chunk_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn big_batches_are_sent_in_several_requests() {
+        let transport = MockTransport::default();
+        let keen = builder(&transport).send_interval(Duration::from_secs(3600)).max_request_bytes(100).build();
+        keen.start();
+        for index in 0..20 {
+            keen.add_event("split", &json!({ "index": index })).unwrap();
+        }
+        assert_eq!(keen.flush(true).unwrap(), 20);
+
+        let requests = transport.requests.lock().unwrap();
+        assert!(requests.len() > 1);
+        for request in requests.iter() {
+            assert!(request.events["split"].len() == 1 || serde_json::to_string(&request.events).unwrap().len() <= 100);
+        }
+        let indexes: Vec<_> = requests.iter().flat_map(|request| request.events["split"].iter().map(|event| event["index"].clone())).collect();
+        assert_eq!(indexes, (0..20).map(|index| json!(index)).collect::<Vec<_>>());
+        drop(requests);
+        assert_eq!(keen.stats().events_sent, 20);
+        keen.stop();
+    }
 }
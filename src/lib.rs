@@ -2,6 +2,12 @@ extern crate curl;
 #[macro_use]
 extern crate log;
 extern crate chrono;
+extern crate flate2;
+extern crate uuid;
+extern crate rand;
+#[cfg(feature = "metrics")]
+#[macro_use]
+extern crate metrics;
 pub extern crate serde;
 #[macro_use]
 pub extern crate serde_json;
@@ -9,5 +15,10 @@ pub extern crate serde_json;
 pub extern crate serde_derive;
 
 pub mod keenio;
+pub mod clock;
+#[cfg(feature = "ffi")]
 pub mod ffi;
+pub mod transport;
+mod spool;
+mod metrics_recorder;
 
@@ -0,0 +1,40 @@
+// Counters of the client reported to the metrics crate facade with the metrics feature. Without
+// it, the functions do nothing.
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+pub(crate) fn events_accepted(events_qty: usize) {
+    counter!("analytics_rs.events_accepted", events_qty as u64);
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn pending_events(events_qty: usize) {
+    gauge!("analytics_rs.pending_events", events_qty as f64);
+}
+
+// events_qty is the number of events in the request, events_sent the ones accepted by Keen
+#[cfg(feature = "metrics")]
+pub(crate) fn batch_sent(events_qty: usize, events_sent: usize, duration: Duration) {
+    counter!("analytics_rs.events_sent", events_sent as u64);
+    histogram!("analytics_rs.batch_size", events_qty as f64);
+    histogram!("analytics_rs.send_duration_seconds", duration.as_secs_f64());
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn send_failed(events_qty: usize, duration: Duration) {
+    increment_counter!("analytics_rs.send_failures");
+    histogram!("analytics_rs.batch_size", events_qty as f64);
+    histogram!("analytics_rs.send_duration_seconds", duration.as_secs_f64());
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn events_accepted(_events_qty: usize) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn pending_events(_events_qty: usize) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn batch_sent(_events_qty: usize, _events_sent: usize, _duration: Duration) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn send_failed(_events_qty: usize, _duration: Duration) {}
@@ -0,0 +1,123 @@
+use keenio::Error;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SPOOL_FILE_NAME: &str = "events.spool";
+
+#[derive(Serialize, Deserialize)]
+struct SpooledEvent {
+    collection: String,
+    event: serde_json::Value,
+}
+
+// Append-only file keeping a copy of the events buffered by the thread, one json per line.
+// The file is made of the events of the batches that failed, followed by the events of the
+// batch being built. Only the current batch is removed when it has been sent successfully.
+pub(crate) struct EventSpool {
+    file: File,
+    batch_offset: u64,
+    held_offset: Option<u64>, // Start of the held events written by hold_events
+}
+
+impl EventSpool {
+    // Open (or create) the spool and return the events left by a previous run
+    pub(crate) fn open(directory: &Path) -> Result<(EventSpool, Vec<(String, serde_json::Value)>), Error> {
+        fs::create_dir_all(directory)?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(directory.join(SPOOL_FILE_NAME))?;
+
+        let mut events = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            // A line can be truncated if the process has been killed while writing it
+            match serde_json::from_str::<SpooledEvent>(&line) {
+                Ok(spooled) => events.push((spooled.collection, spooled.event)),
+                Err(e) => warn!("Spooled event can't be read: {}", e),
+            }
+        }
+
+        // Replayed events are part of the next batch
+        Ok((EventSpool { file, batch_offset: 0, held_offset: None }, events))
+    }
+
+    pub(crate) fn append(&mut self, collection: &str, event: &serde_json::Value) -> Result<(), Error> {
+        self.write_event(collection, event)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Rewrite the current batch with the events being sent first, followed by the held events.
+    // The held events are not kept with the batch if it fails.
+    pub(crate) fn hold_events(
+        &mut self,
+        events: &HashMap<String, Vec<serde_json::Value>>,
+        held_events: &HashMap<String, Vec<serde_json::Value>>,
+    ) -> Result<(), Error> {
+        self.file.set_len(self.batch_offset)?;
+        for (collection, jsons) in events {
+            for json in jsons {
+                self.write_event(collection, json)?;
+            }
+        }
+
+        self.held_offset = Some(self.file.metadata()?.len());
+        for (collection, jsons) in held_events {
+            for json in jsons {
+                self.write_event(collection, json)?;
+            }
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn write_event(&mut self, collection: &str, event: &serde_json::Value) -> Result<(), Error> {
+        let spooled = SpooledEvent {
+            collection: collection.to_owned(),
+            event: event.clone(),
+        };
+
+        let mut line = serde_json::to_string(&spooled)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    // Remove the events of the current batch
+    pub(crate) fn batch_sent(&mut self) -> Result<(), Error> {
+        self.held_offset = None;
+        self.file.set_len(self.batch_offset)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Remove all the events, sent or not
+    pub(crate) fn clear(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.batch_offset = 0;
+        self.held_offset = None;
+        Ok(())
+    }
+
+    // Keep the events of the current batch, they will be replayed on the next start. The held
+    // events stay in the next batch.
+    pub(crate) fn batch_failed(&mut self) -> Result<(), Error> {
+        self.batch_offset = match self.held_offset.take() {
+            Some(held_offset) => held_offset,
+            None => self.file.metadata()?.len(),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use curl::easy::{Easy, HttpVersion, IpResolve, List};
+use keenio::{Error, IpVersion, ProjectSettings};
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Warn when this number of requests in a row had to wait for the rate limiter
+const RATE_LIMITED_WARNING_REQUESTS: u32 = 10;
+
+pub struct Response {
+    pub status: u32,
+    pub body: String,
+}
+
+// Sends the requests built by the client. The settings give the connection options (TLS, proxy,
+// timeout) of the project the request is sent to.
+pub trait Transport: Send {
+    fn post(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)], body: &[u8]) -> Result<Response, Error>;
+    fn get(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)]) -> Result<Response, Error>;
+}
+
+// Default transport, based on libcurl
+pub struct CurlTransport {
+    // Reused for all the requests to keep the connection alive
+    easy: Easy,
+}
+
+impl Default for CurlTransport {
+    fn default() -> Self {
+        CurlTransport::new()
+    }
+}
+
+impl CurlTransport {
+    pub fn new() -> CurlTransport {
+        CurlTransport { easy: Easy::new() }
+    }
+
+    // Set the options common to all the requests
+    fn prepare_request(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)]) -> Result<(), Error> {
+        let easy = &mut self.easy;
+
+        // Reset the options of the previous request, the open connections are kept.
+        easy.reset();
+
+        // The TLS options only apply to https. A http custom domain (ex: a local mock of the
+        // Keen api) is requested in plain HTTP.
+        if url.starts_with("https://") {
+            if settings.ssl_verification() {
+                if let Some(ca_bundle) = settings.ca_bundle() {
+                    easy.cainfo(ca_bundle)?;
+                }
+            } else {
+                let _ = easy.ssl_verify_host(false);
+                let _ = easy.ssl_verify_peer(false);
+            }
+        }
+
+        // Don't block the thread forever if the server stops responding
+        let request_timeout = settings.request_timeout().unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        easy.timeout(request_timeout)?;
+        easy.connect_timeout(request_timeout)?;
+
+        easy.url(url)?;
+
+        if let Some(dns_cache_timeout) = settings.dns_cache_timeout() {
+            easy.dns_cache_timeout(dns_cache_timeout)?;
+        }
+
+        easy.ip_resolve(match settings.ip_version() {
+            IpVersion::Any => IpResolve::Any,
+            IpVersion::V4 => IpResolve::V4,
+            IpVersion::V6 => IpResolve::V6,
+        })?;
+
+        if settings.http2() {
+            easy.http_version(HttpVersion::V2TLS)?;
+        }
+
+        match settings.proxy_url() {
+            Some(proxy_url) => {
+                easy.proxy(proxy_url)?;
+                if let Some((username, password)) = settings.proxy_credentials() {
+                    easy.proxy_username(username)?;
+                    easy.proxy_password(password)?;
+                }
+            }
+            None => {
+                if let Some(proxy_url) = proxy_from_env(url) {
+                    easy.proxy(&proxy_url)?;
+                }
+            }
+        }
+
+        let mut list = List::new();
+        for (name, value) in headers {
+            list.append(&format!("{}: {}", name, value))?;
+        }
+        // curl waits for a "100 Continue" before sending a big body read by the callback, the
+        // Expect header without value disables it
+        list.append("Expect:")?;
+        easy.http_headers(list)?;
+
+        Ok(())
+    }
+
+    // Send the request and return the response. The request body is read by curl from the
+    // buffer of the caller instead of being copied.
+    fn perform_request(&mut self, request_body: Option<&[u8]>) -> Result<Response, Error> {
+        let mut body = Vec::new();
+        {
+            let mut transfer = self.easy.transfer();
+            if let Some(mut request_body) = request_body {
+                transfer.read_function(move |into| {
+                    let read_size = into.len().min(request_body.len());
+                    into[..read_size].copy_from_slice(&request_body[..read_size]);
+                    request_body = &request_body[read_size..];
+                    Ok(read_size)
+                })?;
+            }
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()?;
+        }
+
+        Ok(Response {
+            status: self.easy.response_code()?,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
+
+impl Transport for CurlTransport {
+    fn post(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)], body: &[u8]) -> Result<Response, Error> {
+        self.prepare_request(settings, url, headers)?;
+        self.easy.post(true)?;
+        self.easy.post_field_size(body.len() as u64)?;
+        self.perform_request(Some(body))
+    }
+
+    fn get(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)]) -> Result<Response, Error> {
+        self.prepare_request(settings, url, headers)?;
+        self.easy.get(true)?;
+        self.perform_request(None)
+    }
+}
+
+// curl only reads the lowercase http_proxy variable, look for both cases
+fn proxy_from_env(url: &str) -> Option<String> {
+    let names: &[&str] = if url.starts_with("https://") {
+        &["HTTPS_PROXY", "https_proxy"]
+    } else {
+        &["HTTP_PROXY", "http_proxy"]
+    };
+
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|proxy_url| !proxy_url.is_empty())
+}
+
+// Wait before each request so at most max_requests_per_second are sent (token bucket, bursts of
+// one second of requests are allowed)
+pub(crate) struct RateLimitedTransport {
+    transport: Box<dyn Transport>,
+    max_requests_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+    limited_requests: u32,
+}
+
+impl RateLimitedTransport {
+    pub(crate) fn new(transport: Box<dyn Transport>, max_requests_per_second: f64) -> RateLimitedTransport {
+        RateLimitedTransport {
+            transport,
+            max_requests_per_second,
+            tokens: max_requests_per_second.max(1.0),
+            last_refill: Instant::now(),
+            limited_requests: 0,
+        }
+    }
+
+    fn wait_token(&mut self) {
+        let capacity = self.max_requests_per_second.max(1.0);
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.last_refill).as_secs_f64() * self.max_requests_per_second).min(capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.max_requests_per_second);
+            self.limited_requests += 1;
+            if self.limited_requests.is_multiple_of(RATE_LIMITED_WARNING_REQUESTS) {
+                warn!("Requests are rate limited to {}/s, the last {} requests waited", self.max_requests_per_second, self.limited_requests);
+            }
+            thread::sleep(wait);
+            self.tokens = 1.0;
+            self.last_refill = Instant::now();
+        } else {
+            self.limited_requests = 0;
+        }
+
+        self.tokens -= 1.0;
+    }
+}
+
+impl Transport for RateLimitedTransport {
+    fn post(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)], body: &[u8]) -> Result<Response, Error> {
+        self.wait_token();
+        self.transport.post(settings, url, headers, body)
+    }
+
+    fn get(&mut self, settings: &ProjectSettings, url: &str, headers: &[(String, String)]) -> Result<Response, Error> {
+        self.wait_token();
+        self.transport.get(settings, url, headers)
+    }
+}